@@ -0,0 +1,136 @@
+use log::{info, warn};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::config::CircuitBreakerConfig;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CircuitState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug)]
+enum State {
+    Closed { window_start: Instant, requests: u32, failures: u32 },
+    Open { until: Instant },
+    HalfOpen { since: Instant, attempts: u32, successes: u32 },
+}
+
+/// Stops sending requests to an upstream whose failure ratio crosses the
+/// threshold, then lets a few trial requests through after a cool-down to
+/// decide whether it has recovered.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    name: String,
+    config: CircuitBreakerConfig,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(name: &str, config: CircuitBreakerConfig) -> Self {
+        CircuitBreaker {
+            name: name.to_string(),
+            config,
+            state: Mutex::new(closed()),
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        match *self.state.lock().unwrap() {
+            State::Closed { .. } => CircuitState::Closed,
+            State::Open { .. } => CircuitState::Open,
+            State::HalfOpen { .. } => CircuitState::HalfOpen,
+        }
+    }
+
+    /// Whether a request may be sent now. In the half-open state this takes
+    /// one of the trial slots; trials that never report back are given up on
+    /// after another cool-down.
+    pub fn try_acquire(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        match &mut *state {
+            State::Closed { .. } => true,
+            State::Open { until } => {
+                if now < *until {
+                    return false;
+                }
+                info!("Circuit for upstream '{}' is half-open", self.name);
+                *state = State::HalfOpen { since: now, attempts: 1, successes: 0 };
+                true
+            }
+            State::HalfOpen { since, attempts, successes } => {
+                if *attempts < self.config.half_open_requests.max(1) {
+                    *attempts += 1;
+                    true
+                } else if now.duration_since(*since) >= self.open_duration() {
+                    *state = State::HalfOpen { since: now, attempts: 1, successes: *successes };
+                    true
+                } else {
+                    false
+                }
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        match &mut *state {
+            State::Closed { .. } => self.count(&mut state, false),
+            State::Open { .. } => {}
+            State::HalfOpen { successes, .. } => {
+                *successes += 1;
+                if *successes >= self.config.half_open_requests.max(1) {
+                    info!("Circuit for upstream '{}' closed", self.name);
+                    *state = closed();
+                }
+            }
+        }
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        match &*state {
+            State::Closed { .. } => self.count(&mut state, true),
+            State::Open { .. } => {}
+            State::HalfOpen { .. } => {
+                warn!("Trial request to upstream '{}' failed, reopening circuit", self.name);
+                *state = State::Open { until: Instant::now() + self.open_duration() };
+            }
+        }
+    }
+
+    /// Counts a request in the closed state's window, opening the circuit
+    /// once the window has enough requests and too many of them failed.
+    fn count(&self, state: &mut State, failed: bool) {
+        let now = Instant::now();
+        if let State::Closed { window_start, requests, failures } = state {
+            if now.duration_since(*window_start) >= Duration::from_secs(self.config.window_seconds) {
+                *state = State::Closed { window_start: now, requests: 0, failures: 0 };
+                return self.count(state, failed);
+            }
+            *requests += 1;
+            if failed {
+                *failures += 1;
+            }
+            if *requests >= self.config.min_requests
+                && f64::from(*failures) / f64::from(*requests) >= self.config.failure_ratio
+            {
+                warn!(
+                    "Opening circuit for upstream '{}' after {} of {} requests failed",
+                    self.name, failures, requests
+                );
+                *state = State::Open { until: now + self.open_duration() };
+            }
+        }
+    }
+
+    fn open_duration(&self) -> Duration {
+        Duration::from_secs(self.config.open_seconds)
+    }
+}
+
+fn closed() -> State {
+    State::Closed { window_start: Instant::now(), requests: 0, failures: 0 }
+}
@@ -1,37 +1,472 @@
+use std::collections::HashMap;
 use std::env;
+use std::fs;
 use dotenv::dotenv;
+use serde::Deserialize;
+use crate::handlers::buffer::OverflowPolicy;
+use crate::handlers::headers::ForwardedHeaders;
+use crate::rate_limit::{RateLimitAlgorithm, RateLimitKey, RateLimitSettings, RedisFailurePolicy};
+use crate::routing::RouteKind;
+use crate::upstream::{HttpVersion, LoadBalancing};
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub database_url: String,
     pub port: u16,
     pub ws_port: u16,
-    pub target_http_url: String,
-    pub target_https_url: String,
-    pub target_ws_url: String,
+    pub proxy: ProxyConfig,
+    pub forwarded_headers: ForwardedHeaders,
     pub http_requests_per_minute: u32,
     pub ws_connections_per_minute: u32,
+    pub http_rate_limit: RateLimitSettings,
+    pub ws_rate_limit: RateLimitSettings,
+    pub quota_rate_limit: RateLimitSettings,
     pub redis_url: String,
+    pub redis_failure_policy: RedisFailurePolicy,
+    pub usage_flush_interval_seconds: u64,
+    pub usage_period_seconds: u64,
+    pub api_keys_reload_seconds: u64,
 }
 
 impl Config {
     pub fn from_env() -> Result<Self, ConfigError> {
         dotenv().ok();
 
+        let proxy = match env::var("PROXY_CONFIG") {
+            Ok(path) => ProxyConfig::from_file(&path)?,
+            Err(env::VarError::NotPresent) => ProxyConfig::from_target_env()?,
+            Err(e) => return Err(e.into()),
+        };
+
         Ok(Config {
             database_url: env::var("DATABASE_URL")?,
             port: parse_env_var("SERVER_PORT")?,
             ws_port: parse_env_var("WS_PORT")?,
-            target_http_url: env::var("TARGET_HTTP_URL")?,
-            target_https_url: env::var("TARGET_HTTPS_URL")?,
-            target_ws_url: env::var("TARGET_WS_URL")?,
+            proxy,
+            forwarded_headers: parse_env_var_or("FORWARDED_HEADERS", ForwardedHeaders::Both)?,
             http_requests_per_minute: parse_env_var("HTTP_REQUESTS_PER_MINUTE")?,
             ws_connections_per_minute: parse_env_var("WS_CONNECTIONS_PER_MINUTE")?,
+            http_rate_limit: rate_limit_settings("HTTP", RateLimitKey::Ip)?,
+            ws_rate_limit: rate_limit_settings("WS", RateLimitKey::Ip)?,
+            quota_rate_limit: rate_limit_settings("QUOTA", RateLimitKey::ApiKey)?,
             redis_url: env::var("REDIS_URL")?,
+            redis_failure_policy: parse_env_var_or("RATE_LIMIT_REDIS_FAILURE", RedisFailurePolicy::LocalFallback)?,
+            usage_flush_interval_seconds: parse_env_var_or("USAGE_FLUSH_INTERVAL_SECONDS", 10)?,
+            usage_period_seconds: parse_env_var_or("USAGE_PERIOD_SECONDS", 30 * 24 * 60 * 60)?,
+            api_keys_reload_seconds: parse_env_var_or("API_KEYS_RELOAD_SECONDS", 60)?,
         })
     }
 }
 
+/// Upstreams and routes, read from the TOML file named by `PROXY_CONFIG`.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyConfig {
+    #[serde(default)]
+    pub upstreams: HashMap<String, UpstreamConfig>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    #[serde(default)]
+    pub errors: ErrorConfig,
+    /// Serves HTTPS alongside the plain listener on `SERVER_PORT`.
+    pub tls: Option<TlsConfig>,
+    /// Serves `kind = "grpc"` routes on a listener of their own.
+    pub grpc: Option<GrpcConfig>,
+}
+
+/// The gRPC listener, which speaks HTTP/2 only so responses can carry trailers.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    pub port: u16,
+    /// Terminate TLS with the certificates and client auth of `[tls]`,
+    /// instead of accepting HTTP/2 with prior knowledge (h2c).
+    #[serde(default)]
+    pub tls: bool,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub port: u16,
+    #[serde(default)]
+    pub min_version: TlsVersion,
+    /// How often the certificate and key files are checked for changes.
+    #[serde(default = "default_tls_reload_seconds")]
+    pub reload_seconds: u64,
+    /// Chosen by the name the client asks for (SNI); the first one is used
+    /// when no certificate matches or the client sends no name.
+    pub certificates: Vec<CertificateConfig>,
+    pub client_auth: Option<ClientAuthConfig>,
+}
+
+/// Asks TLS clients for a certificate issued by `ca`. Verified certificates
+/// registered in `client_certificates` authenticate like an API key.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClientAuthConfig {
+    /// PEM file with the CA certificates client certificates must chain to.
+    pub ca: String,
+    /// Refuse TLS connections without a client certificate, instead of
+    /// falling back to `X-Api-Key`.
+    #[serde(default)]
+    pub required: bool,
+}
+
+fn default_tls_reload_seconds() -> u64 {
+    30
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+pub enum TlsVersion {
+    #[default]
+    #[serde(rename = "1.2")]
+    Tls12,
+    #[serde(rename = "1.3")]
+    Tls13,
+}
+
+/// PEM files holding a certificate chain, leaf first, and its private key.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    pub cert: String,
+    pub key: String,
+}
+
+/// Bodies of the responses the proxy sends itself. Templates may use
+/// `{code}`, `{message}`, `{status}` and `{request_id}`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ErrorConfig {
+    pub content_type: String,
+    pub template: String,
+    /// Templates for individual error codes, replacing `template`.
+    pub templates: HashMap<String, String>,
+    /// Messages for individual error codes, replacing the built-in ones.
+    pub messages: HashMap<String, String>,
+}
+
+impl Default for ErrorConfig {
+    fn default() -> Self {
+        ErrorConfig {
+            content_type: "application/json".to_string(),
+            template: r#"{"error":{"code":"{code}","message":"{message}","request_id":"{request_id}"}}"#.to_string(),
+            templates: HashMap::new(),
+            messages: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    /// Shorthand for a single endpoint.
+    pub url: Option<String>,
+    #[serde(default)]
+    pub endpoints: Vec<EndpointConfig>,
+    #[serde(default)]
+    pub strategy: LoadBalancing,
+    pub health_check: Option<HealthCheckConfig>,
+    pub passive_health_check: Option<PassiveHealthCheckConfig>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    pub tls: Option<UpstreamTlsConfig>,
+    #[serde(default)]
+    pub http_version: HttpVersion,
+}
+
+/// TLS towards the `https://` and `wss://` endpoints of an upstream.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamTlsConfig {
+    /// PEM CA bundle trusted instead of the public web roots.
+    pub ca: Option<String>,
+    /// PEM client certificate chain presented to the upstream, with `key`.
+    pub cert: Option<String>,
+    pub key: Option<String>,
+    /// Name sent as SNI and `Host` and checked against the upstream's
+    /// certificate, instead of the endpoint's host. Connections still go to
+    /// the endpoint's host.
+    pub server_name: Option<String>,
+    /// SHA-256 fingerprints of public keys (SubjectPublicKeyInfo), in hex.
+    /// When set, the upstream's certificate chain must contain one of them.
+    #[serde(default)]
+    pub pins: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EndpointConfig {
+    pub url: String,
+    /// Relative share of traffic for `weighted`, `least_connections` and `consistent_hash`.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// Periodic probes of every endpoint of an upstream.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheckConfig {
+    pub path: String,
+    pub expected_status: u16,
+    pub interval_seconds: u64,
+    pub timeout_seconds: u64,
+    /// Consecutive passing probes before an unhealthy endpoint gets traffic again.
+    pub healthy_threshold: u32,
+    /// Consecutive failing probes before an endpoint stops getting traffic.
+    pub unhealthy_threshold: u32,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        HealthCheckConfig {
+            path: "/health".to_string(),
+            expected_status: 200,
+            interval_seconds: 10,
+            timeout_seconds: 2,
+            healthy_threshold: 2,
+            unhealthy_threshold: 3,
+        }
+    }
+}
+
+/// Ejection of endpoints that fail live traffic with connection errors or `5xx`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PassiveHealthCheckConfig {
+    /// Consecutive failures before the endpoint is ejected.
+    pub max_failures: u32,
+    pub ejection_seconds: u64,
+}
+
+impl Default for PassiveHealthCheckConfig {
+    fn default() -> Self {
+        PassiveHealthCheckConfig {
+            max_failures: 5,
+            ejection_seconds: 30,
+        }
+    }
+}
+
+/// Opens the circuit of an upstream when too many of its requests fail.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreakerConfig {
+    /// Share of failed requests in the window that opens the circuit.
+    pub failure_ratio: f64,
+    /// Requests the window needs before the ratio is applied.
+    pub min_requests: u32,
+    pub window_seconds: u64,
+    /// How long the circuit stays open before trial requests are let through.
+    pub open_seconds: u64,
+    /// Trial requests that must all succeed to close the circuit again.
+    pub half_open_requests: u32,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        CircuitBreakerConfig {
+            failure_ratio: 0.5,
+            min_requests: 20,
+            window_seconds: 30,
+            open_seconds: 30,
+            half_open_requests: 5,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub name: String,
+    pub upstream: String,
+    #[serde(default)]
+    pub kind: RouteKind,
+    pub path_prefix: Option<String>,
+    pub path_regex: Option<String>,
+    /// Exact host, or `*.example.com` for any subdomain.
+    pub host: Option<String>,
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    pub scheme: Option<String>,
+    pub strip_prefix: Option<String>,
+    pub add_prefix: Option<String>,
+    /// Replacement for `path_regex`, e.g. `/v2/$1`.
+    pub rewrite: Option<String>,
+    /// Overrides `FORWARDED_HEADERS` for this route.
+    pub forwarded_headers: Option<ForwardedHeaders>,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    pub retries: Option<RetryConfig>,
+    #[serde(default)]
+    pub sse: SseConfig,
+    #[serde(default)]
+    pub websocket: WebSocketConfig,
+}
+
+/// Upstream timeouts for a route. WebSocket handshakes are bounded by
+/// `connect_ms` plus `first_byte_ms`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutConfig {
+    pub connect_ms: u64,
+    /// Until the upstream's response headers arrive.
+    pub first_byte_ms: u64,
+    /// For the whole exchange, including the response body. Unset by
+    /// default so long-lived streams are not cut off.
+    pub total_ms: Option<u64>,
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        TimeoutConfig {
+            connect_ms: 5_000,
+            first_byte_ms: 30_000,
+            total_ms: None,
+        }
+    }
+}
+
+/// Limits for `text/event-stream` responses on a route.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SseConfig {
+    /// Streams are closed when the upstream sends nothing for this long.
+    pub idle_timeout_ms: u64,
+    /// Open streams per API key on the route; 0 for no limit.
+    pub max_streams_per_key: u32,
+}
+
+impl Default for SseConfig {
+    fn default() -> Self {
+        SseConfig {
+            idle_timeout_ms: 300_000,
+            max_streams_per_key: 0,
+        }
+    }
+}
+
+/// Message buffers between the client and upstream of a WebSocket route.
+#[derive(Clone, Copy, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketConfig {
+    /// Messages from the upstream waiting to be sent to the client.
+    pub client_buffer: usize,
+    /// Messages from the client waiting to be sent to the upstream.
+    pub upstream_buffer: usize,
+    pub overflow: OverflowPolicy,
+    /// Close code sent to the client when `overflow` is `disconnect`:
+    /// 1008 (policy violation) or 1013 (try again later).
+    pub close_code: u16,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            client_buffer: 64,
+            upstream_buffer: 64,
+            overflow: OverflowPolicy::Block,
+            close_code: 1013,
+        }
+    }
+}
+
+/// Retries of idempotent requests, or of requests carrying `idempotency_header`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    pub max_retries: u32,
+    /// Base of the exponential backoff; each wait is jittered between zero and the current step.
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Upstream statuses retried in addition to connection errors and timeouts.
+    pub retry_on: Vec<u16>,
+    /// Retries allowed as a share of the route's requests.
+    pub budget_ratio: f64,
+    /// Retries allowed regardless of traffic, so quiet routes can still retry.
+    pub min_retries_per_second: u32,
+    pub idempotency_header: String,
+    /// Request bodies up to this size are buffered so they can be sent again;
+    /// larger ones are streamed and never retried.
+    pub max_buffered_body_bytes: usize,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        RetryConfig {
+            max_retries: 2,
+            backoff_ms: 25,
+            max_backoff_ms: 1_000,
+            retry_on: vec![502, 503, 504],
+            budget_ratio: 0.2,
+            min_retries_per_second: 1,
+            idempotency_header: "idempotency-key".to_string(),
+            max_buffered_body_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl ProxyConfig {
+    pub fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| ConfigError::File(path.to_string(), e.to_string()))?;
+        Self::from_toml(&contents).map_err(|e| match e {
+            ConfigError::Invalid(message) => ConfigError::File(path.to_string(), message),
+            e => e,
+        })
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self, ConfigError> {
+        toml::from_str(contents).map_err(|e| ConfigError::Invalid(e.to_string()))
+    }
+
+    /// Routes equivalent to the fixed `TARGET_*` upstreams used before routing
+    /// was configurable: `/ws` to `TARGET_WS_URL`, everything else to
+    /// `TARGET_HTTP_URL` or `TARGET_HTTPS_URL` by scheme, minus `/api/v1`.
+    pub fn from_target_env() -> Result<Self, ConfigError> {
+        let upstreams = [
+            ("http", env::var("TARGET_HTTP_URL")?),
+            ("https", env::var("TARGET_HTTPS_URL")?),
+            ("ws", env::var("TARGET_WS_URL")?),
+        ]
+        .into_iter()
+        .map(|(name, url)| (name.to_string(), UpstreamConfig { url: Some(url), ..Default::default() }))
+        .collect();
+
+        let routes = vec![
+            RouteConfig {
+                name: "ws".to_string(),
+                upstream: "ws".to_string(),
+                kind: RouteKind::Websocket,
+                path_prefix: Some("/ws".to_string()),
+                methods: vec!["GET".to_string()],
+                ..Default::default()
+            },
+            RouteConfig {
+                name: "https".to_string(),
+                upstream: "https".to_string(),
+                scheme: Some("https".to_string()),
+                strip_prefix: Some("/api/v1".to_string()),
+                ..Default::default()
+            },
+            RouteConfig {
+                name: "http".to_string(),
+                upstream: "http".to_string(),
+                strip_prefix: Some("/api/v1".to_string()),
+                ..Default::default()
+            },
+        ];
+
+        Ok(ProxyConfig { upstreams, routes, errors: ErrorConfig::default(), tls: None, grpc: None })
+    }
+}
+
 fn parse_env_var<T: std::str::FromStr>(key: &str) -> Result<T, ConfigError>
 where
     T::Err: std::fmt::Debug,
@@ -41,10 +476,44 @@ where
         .map_err(|e| ConfigError::ParseError(key.to_string(), format!("{:?}", e)))
 }
 
+fn parse_env_var_or<T: std::str::FromStr>(key: &str, default: T) -> Result<T, ConfigError>
+where
+    T::Err: std::fmt::Debug,
+{
+    match env::var(key) {
+        Ok(_) => parse_env_var(key),
+        Err(env::VarError::NotPresent) => Ok(default),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn parse_optional_env_var<T: std::str::FromStr>(key: &str) -> Result<Option<T>, ConfigError>
+where
+    T::Err: std::fmt::Debug,
+{
+    match env::var(key) {
+        Ok(_) => parse_env_var(key).map(Some),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Reads `{prefix}_RATE_LIMIT_ALGORITHM` (default `fixed_window`), `{prefix}_RATE_LIMIT_KEY`
+/// and `{prefix}_RATE_LIMIT_BURST`.
+fn rate_limit_settings(prefix: &str, default_key: RateLimitKey) -> Result<RateLimitSettings, ConfigError> {
+    Ok(RateLimitSettings {
+        algorithm: parse_env_var_or(&format!("{}_RATE_LIMIT_ALGORITHM", prefix), RateLimitAlgorithm::FixedWindow)?,
+        key: parse_env_var_or(&format!("{}_RATE_LIMIT_KEY", prefix), default_key)?,
+        burst: parse_optional_env_var(&format!("{}_RATE_LIMIT_BURST", prefix))?,
+    })
+}
+
 #[derive(Debug)]
 pub enum ConfigError {
     EnvVarMissing(env::VarError),
     ParseError(String, String),
+    File(String, String),
+    Invalid(String),
 }
 
 impl From<env::VarError> for ConfigError {
@@ -58,6 +527,8 @@ impl std::fmt::Display for ConfigError {
         match self {
             ConfigError::EnvVarMissing(err) => write!(f, "Environment variable error: {}", err),
             ConfigError::ParseError(key, err) => write!(f, "Failed to parse {}: {}", key, err),
+            ConfigError::File(path, err) => write!(f, "Failed to read {}: {}", path, err),
+            ConfigError::Invalid(err) => write!(f, "Invalid proxy configuration: {}", err),
         }
     }
 }
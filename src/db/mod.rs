@@ -1,11 +1,43 @@
-use tokio_postgres::{Client, Error, NoTls};
+use tokio_postgres::{AsyncMessage, Client, Error, NoTls, Notification};
+use futures::channel::mpsc::{unbounded, UnboundedReceiver};
+use futures::{stream, StreamExt};
 use std::collections::HashMap;
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::time::sleep;
 
 const MAX_CONNECTION_ATTEMPTS: u8 = 5;
 const RETRY_DELAY_SECONDS: u64 = 5;
 
+/// Channel notified by the triggers in `schema.sql` whenever `api_keys`,
+/// `product_features` or `client_certificates` change.
+pub const API_KEYS_CHANNEL: &str = "api_keys_changed";
+
+/// An API key as stored in `api_keys`, without the secret itself.
+#[derive(Clone, Debug)]
+pub struct ApiKey {
+    pub id: i32,
+    pub user_id: Option<i32>,
+    pub product_id: Option<i32>,
+}
+
+/// A `client_certificates` row: a client certificate that authenticates as
+/// `api_key`, on its own or only together with the key itself.
+#[derive(Clone, Debug)]
+pub struct CertificateBinding {
+    pub fingerprint: Option<String>,
+    pub subject: Option<String>,
+    pub api_key: ApiKey,
+    pub require_api_key: bool,
+}
+
+/// A `product_features` row: at most `max_requests` per `period` for `feature`.
+#[derive(Clone, Debug)]
+pub struct Quota {
+    pub feature: String,
+    pub period: Duration,
+    pub max_requests: u32,
+}
+
 pub async fn init_db(client: &Client) -> Result<(), Error> {
     client.batch_execute(include_str!("schema.sql")).await
 }
@@ -33,7 +65,150 @@ pub async fn connect_to_postgres(database_url: &str) -> Result<Client, Error> {
     unreachable!()
 }
 
-pub async fn load_api_keys(client: &Client) -> Result<HashMap<String, bool>, Error> {
-    let rows = client.query("SELECT key FROM api_keys", &[]).await?;
-    Ok(rows.into_iter().map(|row| (row.get(0), true)).collect())
-}
\ No newline at end of file
+/// Opens a dedicated connection that `LISTEN`s on `channel`. The returned
+/// client must be kept alive; the receiver ends when the connection is lost.
+pub async fn listen(database_url: &str, channel: &str) -> Result<(Client, UnboundedReceiver<Notification>), Error> {
+    let (client, mut connection) = tokio_postgres::connect(database_url, NoTls).await?;
+    let (tx, rx) = unbounded();
+
+    tokio::spawn(async move {
+        let mut messages = stream::poll_fn(move |cx| connection.poll_message(cx));
+        while let Some(message) = messages.next().await {
+            match message {
+                Ok(AsyncMessage::Notification(notification)) => {
+                    if tx.unbounded_send(notification).is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("Postgres listener connection error: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    client.batch_execute(&format!("LISTEN {}", channel)).await?;
+    Ok((client, rx))
+}
+
+pub async fn load_api_keys(client: &Client) -> Result<HashMap<String, ApiKey>, Error> {
+    let rows = client.query("SELECT key, id, user_id, product_id FROM api_keys", &[]).await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| {
+            let api_key = ApiKey {
+                id: row.get(1),
+                user_id: row.get(2),
+                product_id: row.get(3),
+            };
+            (row.get(0), api_key)
+        })
+        .collect())
+}
+
+pub async fn load_client_certificates(client: &Client) -> Result<Vec<CertificateBinding>, Error> {
+    let rows = client
+        .query(
+            "SELECT c.fingerprint, c.subject, c.require_api_key, k.id, k.user_id, k.product_id \
+             FROM client_certificates c JOIN api_keys k ON k.id = c.api_key_id",
+            &[],
+        )
+        .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| CertificateBinding {
+            fingerprint: row.get(0),
+            subject: row.get(1),
+            require_api_key: row.get(2),
+            api_key: ApiKey {
+                id: row.get(3),
+                user_id: row.get(4),
+                product_id: row.get(5),
+            },
+        })
+        .collect())
+}
+
+pub async fn load_product_quotas(client: &Client) -> Result<HashMap<i32, Vec<Quota>>, Error> {
+    let rows = client
+        .query(
+            "SELECT pf.product_id, f.name, EXTRACT(EPOCH FROM pf.period_duration)::BIGINT, pf.max_requests \
+             FROM product_features pf JOIN features f ON f.id = pf.feature_id",
+            &[],
+        )
+        .await?;
+
+    let mut quotas: HashMap<i32, Vec<Quota>> = HashMap::new();
+    for row in rows {
+        let period_seconds: i64 = row.get(2);
+        let max_requests: i32 = row.get(3);
+        quotas.entry(row.get(0)).or_default().push(Quota {
+            feature: row.get(1),
+            period: Duration::from_secs(period_seconds.max(1) as u64),
+            max_requests: max_requests.max(0) as u32,
+        });
+    }
+    Ok(quotas)
+}
+
+/// Returns the id of the period of `product_id` covering `now`, creating one
+/// of `length` aligned to multiples of `length` since the Unix epoch if none
+/// exists. Proxies creating the same period at once all get the same row.
+pub async fn current_period(
+    client: &Client,
+    product_id: Option<i32>,
+    now: SystemTime,
+    length: Duration,
+) -> Result<(i32, SystemTime), Error> {
+    let existing = client
+        .query_opt(
+            "SELECT id, date_end FROM periods \
+             WHERE product_id IS NOT DISTINCT FROM $1 AND date_start <= $2 AND date_end > $2 \
+             ORDER BY date_start DESC LIMIT 1",
+            &[&product_id, &now],
+        )
+        .await?;
+    if let Some(row) = existing {
+        return Ok((row.get(0), row.get(1)));
+    }
+
+    let since_epoch = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let length_seconds = length.as_secs().max(1);
+    let date_start = UNIX_EPOCH + Duration::from_secs(since_epoch - since_epoch % length_seconds);
+    let date_end = date_start + Duration::from_secs(length_seconds);
+
+    // Another proxy may create the same period first; then its row is used
+    let inserted = client
+        .query_opt(
+            "INSERT INTO periods (product_id, date_start, date_end) VALUES ($1, $2, $3) \
+             ON CONFLICT ((COALESCE(product_id, 0)), date_start, date_end) DO NOTHING RETURNING id",
+            &[&product_id, &date_start, &date_end],
+        )
+        .await?;
+    let row = match inserted {
+        Some(row) => row,
+        None => {
+            client
+                .query_one(
+                    "SELECT id FROM periods \
+                     WHERE product_id IS NOT DISTINCT FROM $1 AND date_start = $2 AND date_end = $3",
+                    &[&product_id, &date_start, &date_end],
+                )
+                .await?
+        }
+    };
+    Ok((row.get(0), date_end))
+}
+
+pub async fn add_usage(client: &Client, api_key_id: i32, period_id: i32, request_count: i64) -> Result<(), Error> {
+    client
+        .execute(
+            "INSERT INTO usage (api_key_id, period_id, request_count) VALUES ($1, $2, $3) \
+             ON CONFLICT (api_key_id, period_id) DO UPDATE SET request_count = usage.request_count + EXCLUDED.request_count",
+            &[&api_key_id, &period_id, &request_count],
+        )
+        .await?;
+    Ok(())
+}
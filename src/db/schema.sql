@@ -39,6 +39,74 @@ CREATE TABLE IF NOT EXISTS periods (
 CREATE TABLE IF NOT EXISTS usage (
     api_key_id INTEGER REFERENCES api_keys(id),
     period_id INTEGER REFERENCES periods(id),
-    request_count INTEGER NOT NULL,
+    request_count BIGINT NOT NULL,
     PRIMARY KEY (api_key_id, period_id)
-);
\ No newline at end of file
+);
+
+-- One period per product and range, so proxies rolling over at the same time
+-- share it. Duplicates left by earlier versions are merged into the oldest.
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM pg_indexes WHERE indexname = 'periods_product_range') THEN
+        WITH duplicates AS (
+            SELECT id, MIN(id) OVER (PARTITION BY COALESCE(product_id, 0), date_start, date_end) AS keeper
+            FROM periods
+        ), moved AS (
+            DELETE FROM usage USING duplicates
+            WHERE usage.period_id = duplicates.id AND duplicates.id <> duplicates.keeper
+            RETURNING usage.api_key_id, duplicates.keeper, usage.request_count
+        )
+        INSERT INTO usage (api_key_id, period_id, request_count)
+        SELECT api_key_id, keeper, SUM(request_count) FROM moved GROUP BY api_key_id, keeper
+        ON CONFLICT (api_key_id, period_id) DO UPDATE SET request_count = usage.request_count + EXCLUDED.request_count;
+
+        DELETE FROM periods USING periods AS kept
+        WHERE COALESCE(periods.product_id, 0) = COALESCE(kept.product_id, 0)
+            AND periods.date_start = kept.date_start AND periods.date_end = kept.date_end
+            AND periods.id > kept.id;
+
+        CREATE UNIQUE INDEX IF NOT EXISTS periods_product_range
+            ON periods ((COALESCE(product_id, 0)), date_start, date_end);
+    END IF;
+END
+$$;
+
+-- Databases created before request_count was widened
+DO $$
+BEGIN
+    IF (SELECT data_type FROM information_schema.columns
+        WHERE table_name = 'usage' AND column_name = 'request_count') = 'integer' THEN
+        ALTER TABLE usage ALTER COLUMN request_count TYPE BIGINT;
+    END IF;
+END
+$$;
+
+-- Client certificates that authenticate as an API key, matched by SHA-256
+-- fingerprint (hex, colons optional) or by RFC 4514 subject.
+CREATE TABLE IF NOT EXISTS client_certificates (
+    id SERIAL PRIMARY KEY,
+    api_key_id INTEGER NOT NULL REFERENCES api_keys(id) ON DELETE CASCADE,
+    fingerprint VARCHAR(128) UNIQUE,
+    subject VARCHAR(1024) UNIQUE,
+    require_api_key BOOLEAN NOT NULL DEFAULT FALSE,
+    CHECK (fingerprint IS NOT NULL OR subject IS NOT NULL)
+);
+
+CREATE OR REPLACE FUNCTION notify_api_keys_changed() RETURNS trigger AS $$
+BEGIN
+    PERFORM pg_notify('api_keys_changed', TG_TABLE_NAME);
+    RETURN NULL;
+END;
+$$ LANGUAGE plpgsql;
+
+DROP TRIGGER IF EXISTS api_keys_changed ON api_keys;
+CREATE TRIGGER api_keys_changed AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON api_keys
+    FOR EACH STATEMENT EXECUTE PROCEDURE notify_api_keys_changed();
+
+DROP TRIGGER IF EXISTS product_features_changed ON product_features;
+CREATE TRIGGER product_features_changed AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON product_features
+    FOR EACH STATEMENT EXECUTE PROCEDURE notify_api_keys_changed();
+
+DROP TRIGGER IF EXISTS client_certificates_changed ON client_certificates;
+CREATE TRIGGER client_certificates_changed AFTER INSERT OR UPDATE OR DELETE OR TRUNCATE ON client_certificates
+    FOR EACH STATEMENT EXECUTE PROCEDURE notify_api_keys_changed();
@@ -0,0 +1,237 @@
+use actix_web::http::header::{HeaderValue, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use rand::Rng;
+use std::collections::HashMap;
+use crate::config::{ConfigError, ErrorConfig};
+use crate::upstream::Unavailable;
+
+/// Header carrying the id that ties a response to the proxy's logs.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Errors the proxy answers itself, each with a stable code for clients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProxyError {
+    MissingApiKey,
+    InvalidApiKey,
+    InvalidClientCertificate,
+    NoRoute,
+    MethodNotAllowed,
+    BadRequest,
+    RateLimited,
+    TooManyStreams,
+    RateLimiterUnavailable,
+    CircuitOpen,
+    NoHealthyUpstream,
+    UpstreamUnreachable,
+    UpstreamTimeout,
+    BadGateway,
+}
+
+impl ProxyError {
+    pub const ALL: [ProxyError; 14] = [
+        ProxyError::MissingApiKey,
+        ProxyError::InvalidApiKey,
+        ProxyError::InvalidClientCertificate,
+        ProxyError::NoRoute,
+        ProxyError::MethodNotAllowed,
+        ProxyError::BadRequest,
+        ProxyError::RateLimited,
+        ProxyError::TooManyStreams,
+        ProxyError::RateLimiterUnavailable,
+        ProxyError::CircuitOpen,
+        ProxyError::NoHealthyUpstream,
+        ProxyError::UpstreamUnreachable,
+        ProxyError::UpstreamTimeout,
+        ProxyError::BadGateway,
+    ];
+
+    pub fn code(self) -> &'static str {
+        match self {
+            ProxyError::MissingApiKey => "missing_api_key",
+            ProxyError::InvalidApiKey => "invalid_api_key",
+            ProxyError::InvalidClientCertificate => "invalid_client_certificate",
+            ProxyError::NoRoute => "no_route",
+            ProxyError::MethodNotAllowed => "method_not_allowed",
+            ProxyError::BadRequest => "bad_request",
+            ProxyError::RateLimited => "rate_limited",
+            ProxyError::TooManyStreams => "too_many_streams",
+            ProxyError::RateLimiterUnavailable => "rate_limiter_unavailable",
+            ProxyError::CircuitOpen => "circuit_open",
+            ProxyError::NoHealthyUpstream => "no_healthy_upstream",
+            ProxyError::UpstreamUnreachable => "upstream_unreachable",
+            ProxyError::UpstreamTimeout => "upstream_timeout",
+            ProxyError::BadGateway => "bad_gateway",
+        }
+    }
+
+    pub fn status(self) -> StatusCode {
+        match self {
+            ProxyError::MissingApiKey | ProxyError::InvalidApiKey | ProxyError::InvalidClientCertificate => {
+                StatusCode::UNAUTHORIZED
+            }
+            ProxyError::NoRoute => StatusCode::NOT_FOUND,
+            ProxyError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ProxyError::BadRequest => StatusCode::BAD_REQUEST,
+            ProxyError::RateLimited | ProxyError::TooManyStreams => StatusCode::TOO_MANY_REQUESTS,
+            ProxyError::RateLimiterUnavailable | ProxyError::CircuitOpen | ProxyError::NoHealthyUpstream => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ProxyError::UpstreamUnreachable | ProxyError::BadGateway => StatusCode::BAD_GATEWAY,
+            ProxyError::UpstreamTimeout => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            ProxyError::MissingApiKey => "Missing API key",
+            ProxyError::InvalidApiKey => "Invalid API key",
+            ProxyError::InvalidClientCertificate => "Client certificate is not registered or belongs to another API key",
+            ProxyError::NoRoute => "No route matches this request",
+            ProxyError::MethodNotAllowed => "Method not allowed",
+            ProxyError::BadRequest => "The request body could not be read",
+            ProxyError::RateLimited => "Rate limit exceeded",
+            ProxyError::TooManyStreams => "Too many open event streams for this API key",
+            ProxyError::RateLimiterUnavailable => "Rate limiting is temporarily unavailable",
+            ProxyError::CircuitOpen => "The upstream service is temporarily unavailable",
+            ProxyError::NoHealthyUpstream => "No healthy upstream service is available",
+            ProxyError::UpstreamUnreachable => "The upstream service could not be reached",
+            ProxyError::UpstreamTimeout => "The upstream service did not respond in time",
+            ProxyError::BadGateway => "The upstream service failed to respond",
+        }
+    }
+
+    /// The gRPC status code for the error, as sent in `grpc-status`.
+    pub fn grpc_status(self) -> u32 {
+        match self {
+            ProxyError::MissingApiKey | ProxyError::InvalidApiKey | ProxyError::InvalidClientCertificate => 16, // UNAUTHENTICATED
+            ProxyError::NoRoute | ProxyError::MethodNotAllowed => 12, // UNIMPLEMENTED
+            ProxyError::BadRequest => 13, // INTERNAL
+            ProxyError::RateLimited | ProxyError::TooManyStreams => 8, // RESOURCE_EXHAUSTED
+            ProxyError::UpstreamTimeout => 4, // DEADLINE_EXCEEDED
+            ProxyError::RateLimiterUnavailable
+            | ProxyError::CircuitOpen
+            | ProxyError::NoHealthyUpstream
+            | ProxyError::UpstreamUnreachable
+            | ProxyError::BadGateway => 14, // UNAVAILABLE
+        }
+    }
+
+    fn from_code(code: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|error| error.code() == code)
+    }
+}
+
+impl From<Unavailable> for ProxyError {
+    fn from(unavailable: Unavailable) -> Self {
+        match unavailable {
+            Unavailable::CircuitOpen => ProxyError::CircuitOpen,
+            Unavailable::NoHealthyEndpoint => ProxyError::NoHealthyUpstream,
+        }
+    }
+}
+
+impl std::fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+/// Rendered with the default template and no request id. `Middleware`
+/// replaces these responses with ones from the configured templates.
+impl ResponseError for ProxyError {
+    fn status_code(&self) -> StatusCode {
+        self.status()
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        ErrorResponses::default().response(*self, "")
+    }
+}
+
+/// Renders `ProxyError`s from templates, in which `{code}`, `{message}`,
+/// `{status}` and `{request_id}` are replaced.
+#[derive(Debug)]
+pub struct ErrorResponses {
+    content_type: HeaderValue,
+    escape_json: bool,
+    template: String,
+    templates: HashMap<&'static str, String>,
+    messages: HashMap<&'static str, String>,
+}
+
+impl ErrorResponses {
+    pub fn new(config: &ErrorConfig) -> Result<Self, ConfigError> {
+        let content_type = HeaderValue::from_str(&config.content_type)
+            .map_err(|_| ConfigError::Invalid(format!("invalid error content type '{}'", config.content_type)))?;
+        let by_code = |overrides: &HashMap<String, String>| {
+            overrides
+                .iter()
+                .map(|(code, value)| match ProxyError::from_code(code) {
+                    Some(error) => Ok((error.code(), value.clone())),
+                    None => Err(ConfigError::Invalid(format!("unknown error code '{}'", code))),
+                })
+                .collect::<Result<HashMap<_, _>, _>>()
+        };
+
+        Ok(ErrorResponses {
+            escape_json: config.content_type.contains("json"),
+            content_type,
+            template: config.template.clone(),
+            templates: by_code(&config.templates)?,
+            messages: by_code(&config.messages)?,
+        })
+    }
+
+    pub fn response(&self, error: ProxyError, request_id: &str) -> HttpResponse {
+        let template = self.templates.get(error.code()).unwrap_or(&self.template);
+        let message = self.message(error);
+        let body = template
+            .replace("{code}", error.code())
+            .replace("{status}", error.status().as_str())
+            .replace("{message}", &self.escape(message))
+            .replace("{request_id}", &self.escape(request_id));
+
+        HttpResponse::build(error.status())
+            .insert_header((CONTENT_TYPE, self.content_type.clone()))
+            .body(body)
+    }
+
+    /// The configured message for `error`, or its default one.
+    pub fn message(&self, error: ProxyError) -> &str {
+        self.messages.get(error.code()).map(String::as_str).unwrap_or(error.message())
+    }
+
+    fn escape(&self, value: &str) -> String {
+        if !self.escape_json {
+            return value.to_string();
+        }
+        let quoted = serde_json::to_string(value).unwrap_or_default();
+        quoted[1..quoted.len() - 1].to_string()
+    }
+}
+
+impl Default for ErrorResponses {
+    fn default() -> Self {
+        ErrorResponses::new(&ErrorConfig::default()).expect("default error templates are valid")
+    }
+}
+
+/// The request's id: the client's `X-Request-Id` when it is a reasonable
+/// token, otherwise a new random one.
+pub fn request_id(existing: Option<&str>) -> String {
+    match existing {
+        Some(id) if is_valid_request_id(id) => id.to_string(),
+        _ => format!("{:032x}", rand::thread_rng().gen::<u128>()),
+    }
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 128
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || b"-_.:".contains(&b))
+}
+
+/// Stored in the request extensions by `Middleware`.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
@@ -0,0 +1,162 @@
+use futures::Stream;
+use log::debug;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use tokio_tungstenite::tungstenite::Message;
+
+/// What a full WebSocket buffer does with the next message.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OverflowPolicy {
+    /// Stop reading from the sender until there is room again.
+    #[default]
+    Block,
+    /// Discard the oldest queued text or binary message to make room, or
+    /// block if only control frames are queued.
+    DropOldest,
+    /// Close the connection.
+    Disconnect,
+}
+
+/// Messages a buffer holds; only data may be dropped on overflow.
+pub trait BufferedMessage {
+    fn is_data(&self) -> bool;
+}
+
+/// Text and binary messages; control frames such as `Close` are never dropped.
+impl BufferedMessage for Message {
+    fn is_data(&self) -> bool {
+        matches!(self, Message::Text(_) | Message::Binary(_))
+    }
+}
+
+/// Why a message was not queued.
+#[derive(Debug, PartialEq, Eq)]
+pub enum PushError {
+    /// The buffer is full and its policy is to disconnect.
+    Full,
+    Closed,
+}
+
+/// A bounded queue of messages from one WebSocket peer to the other, with
+/// a single producer and a single consumer.
+#[derive(Debug)]
+pub struct MessageBuffer<T> {
+    capacity: usize,
+    policy: OverflowPolicy,
+    state: Mutex<State<T>>,
+    /// Signalled when a message is queued or the buffer is closed.
+    readable: Notify,
+    /// Signalled when a message is taken or the buffer is closed.
+    writable: Notify,
+}
+
+#[derive(Debug)]
+struct State<T> {
+    messages: VecDeque<T>,
+    closed: bool,
+}
+
+impl<T: BufferedMessage> MessageBuffer<T> {
+    pub fn new(capacity: usize, policy: OverflowPolicy) -> Self {
+        MessageBuffer {
+            capacity,
+            policy,
+            state: Mutex::new(State { messages: VecDeque::with_capacity(capacity), closed: false }),
+            readable: Notify::new(),
+            writable: Notify::new(),
+        }
+    }
+
+    /// Queues `message`, applying the overflow policy when the buffer is
+    /// full: waiting for room, dropping the oldest message, or failing.
+    pub async fn push(&self, message: T) -> Result<(), PushError> {
+        let mut message = Some(message);
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return Err(PushError::Closed);
+                }
+                if state.messages.len() >= self.capacity {
+                    match self.policy {
+                        OverflowPolicy::Block => {}
+                        OverflowPolicy::DropOldest => {
+                            if let Some(oldest) = state.messages.iter().position(T::is_data) {
+                                state.messages.remove(oldest);
+                                debug!("WebSocket buffer full, dropped its oldest message");
+                            }
+                        }
+                        OverflowPolicy::Disconnect => return Err(PushError::Full),
+                    }
+                }
+                if state.messages.len() < self.capacity {
+                    state.messages.extend(message.take());
+                    drop(state);
+                    self.readable.notify_one();
+                    return Ok(());
+                }
+            }
+            self.writable.notified().await;
+        }
+    }
+
+    /// Takes the oldest message, waiting for one to arrive. Returns `None`
+    /// once the buffer is closed and empty.
+    pub async fn pop(&self) -> Option<T> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if let Some(message) = state.messages.pop_front() {
+                    drop(state);
+                    self.writable.notify_one();
+                    return Some(message);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.readable.notified().await;
+        }
+    }
+
+    /// The buffer's messages as a stream, which ends once it is closed and empty.
+    pub fn into_stream(buffer: Arc<Self>) -> impl Stream<Item = T> {
+        futures::stream::unfold(buffer, |buffer| async move {
+            let message = buffer.pop().await?;
+            Some((message, buffer))
+        })
+    }
+
+    /// Refuses further messages; those already queued can still be taken.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.readable.notify_one();
+        self.writable.notify_one();
+    }
+
+    /// Queues `message` as the last one, whatever the capacity and policy,
+    /// and closes the buffer. Does nothing if it is already closed.
+    pub fn close_with(&self, message: T) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.closed {
+                return;
+            }
+            state.messages.push_back(message);
+            state.closed = true;
+        }
+        self.readable.notify_one();
+        self.writable.notify_one();
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
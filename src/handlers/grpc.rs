@@ -0,0 +1,295 @@
+use http::header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, CONTENT_TYPE, HOST, TE};
+use http::{Request, Response, StatusCode};
+use http_body_util::combinators::UnsyncBoxBody;
+use http_body_util::{BodyDataStream, BodyExt, Empty};
+use hyper::body::{Bytes, Incoming};
+use hyper::server::conn::http2;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use log::{debug, error};
+use std::convert::Infallible;
+use std::error::Error as StdError;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::TlsAcceptor;
+use crate::config::{ConfigError, GrpcConfig};
+use crate::db::ApiKey;
+use crate::errors::{self, ProxyError, REQUEST_ID_HEADER};
+use crate::middleware::{Admitted, Middleware};
+use crate::routing::{Route, RouteRequest};
+use crate::tls::ClientCertificate;
+use super::headers;
+
+/// Time allowed for a client to complete the TLS handshake.
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+
+type ResponseBody = UnsyncBoxBody<Bytes, Box<dyn StdError + Send + Sync>>;
+
+/// The client side of a connection to the gRPC listener.
+struct Connection {
+    peer: SocketAddr,
+    secure: bool,
+    certificate: Option<ClientCertificate>,
+}
+
+/// The acceptor for the gRPC listener when `tls` is set: the `[tls]`
+/// listener's configuration, offering `h2` only.
+pub fn tls_acceptor(config: &GrpcConfig, tls: Option<&rustls::ServerConfig>) -> Result<Option<TlsAcceptor>, ConfigError> {
+    if !config.tls {
+        return Ok(None);
+    }
+    let mut server_config = tls
+        .cloned()
+        .ok_or_else(|| ConfigError::Invalid("grpc: tls requires a [tls] section".to_string()))?;
+    server_config.alpn_protocols = vec![b"h2".to_vec()];
+    Ok(Some(TlsAcceptor::from(Arc::new(server_config))))
+}
+
+/// Accepts connections on the gRPC listener and serves them over HTTP/2,
+/// with the same authentication, rate limits and usage accounting as the
+/// HTTP listeners.
+pub async fn serve(listener: TcpListener, tls: Option<TlsAcceptor>, middleware: Middleware) {
+    let middleware = Arc::new(middleware);
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                error!("Failed to accept gRPC connection: {}", e);
+                sleep(Duration::from_millis(100)).await;
+                continue;
+            }
+        };
+        let middleware = Arc::clone(&middleware);
+        let tls = tls.clone();
+        tokio::spawn(async move {
+            let Some(acceptor) = tls else {
+                let connection = Connection { peer, secure: false, certificate: None };
+                return serve_connection(stream, connection, middleware).await;
+            };
+            match timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let certificate = stream.get_ref().1
+                        .peer_certificates()
+                        .and_then(|chain| chain.first())
+                        .and_then(|der| ClientCertificate::from_der(der));
+                    let connection = Connection { peer, secure: true, certificate };
+                    serve_connection(stream, connection, middleware).await
+                }
+                Ok(Err(e)) => debug!("TLS handshake with gRPC client {} failed: {}", peer, e),
+                Err(_) => debug!("TLS handshake with gRPC client {} timed out", peer),
+            }
+        });
+    }
+}
+
+async fn serve_connection<I>(io: I, connection: Connection, middleware: Arc<Middleware>)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let peer = connection.peer;
+    let connection = Arc::new(connection);
+    let service = service_fn(move |req| {
+        let connection = Arc::clone(&connection);
+        let middleware = Arc::clone(&middleware);
+        async move { Ok::<_, Infallible>(handle(req, &connection, &middleware).await) }
+    });
+    if let Err(e) = http2::Builder::new(TokioExecutor::new()).serve_connection(TokioIo::new(io), service).await {
+        debug!("gRPC connection from {} failed: {}", peer, e);
+    }
+}
+
+async fn handle(req: Request<Incoming>, connection: &Connection, middleware: &Middleware) -> Response<ResponseBody> {
+    let request_id = errors::request_id(req.headers().get(REQUEST_ID_HEADER).and_then(|v| v.to_str().ok()));
+    if !is_grpc(req.headers()) {
+        let mut response = Response::new(empty());
+        *response.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+        insert_header(response.headers_mut(), REQUEST_ID_HEADER, &request_id);
+        return response;
+    }
+
+    let route = middleware.routes().find_grpc(&RouteRequest {
+        method: req.method().as_str(),
+        scheme: if connection.secure { "https" } else { "http" },
+        host: authority(&req),
+        path: req.uri().path(),
+        headers: req.headers(),
+    });
+    let api_key = req.headers().get("x-api-key").and_then(|v| v.to_str().ok());
+    let ip = connection.peer.ip().to_string();
+    let (mut response, decision) = match middleware.admit(api_key, connection.certificate.as_ref(), route, &ip).await {
+        Ok(admitted) => {
+            let response = forward(req, connection, &admitted, &request_id)
+                .await
+                .unwrap_or_else(|e| status_response(e, middleware.errors().message(e)));
+            (response, Some(admitted.decision))
+        }
+        Err((e, decision)) => (status_response(e, middleware.errors().message(e)), decision),
+    };
+
+    for (name, value) in decision.iter().flat_map(|decision| decision.headers()) {
+        insert_header(response.headers_mut(), name, &value.to_string());
+    }
+    insert_header(response.headers_mut(), REQUEST_ID_HEADER, &request_id);
+    response
+}
+
+/// Sends the call to an endpoint of the route's upstream and streams the
+/// response back, trailers included. Calls are not retried, as their
+/// bodies are streamed through.
+async fn forward(
+    req: Request<Incoming>,
+    connection: &Connection,
+    admitted: &Admitted,
+    request_id: &str,
+) -> Result<Response<ResponseBody>, ProxyError> {
+    let route = &admitted.route;
+    let upstream = &route.upstream;
+    let endpoint = upstream.select(Some(admitted.api_key.id)).map_err(|e| {
+        error!("Upstream '{}' unavailable: {}", upstream.name, e);
+        ProxyError::from(e)
+    })?;
+    let url = route.upstream_url(&endpoint, req.uri().path(), req.uri().query());
+    debug!("Forwarding gRPC call to {} (route '{}')", url, route.name);
+
+    let upstream_headers = upstream_request_headers(&req, connection, route, &admitted.api_key, request_id);
+    let mut forwarded_req = route.client(&endpoint)
+        .request(req.method().clone(), &url)
+        .headers(upstream_headers)
+        .body(reqwest::Body::wrap_stream(BodyDataStream::new(req.into_body())));
+    if let Some(total) = route.total_timeout() {
+        forwarded_req = forwarded_req.timeout(total);
+    }
+
+    let response = match timeout(route.first_byte_timeout(), forwarded_req.send()).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            upstream.record_failure(&endpoint);
+            error!("Failed to forward gRPC call to upstream '{}': {:?}", upstream.name, e);
+            return Err(if e.is_timeout() {
+                ProxyError::UpstreamTimeout
+            } else if e.is_connect() {
+                ProxyError::UpstreamUnreachable
+            } else {
+                ProxyError::BadGateway
+            });
+        }
+        Err(_) => {
+            upstream.record_failure(&endpoint);
+            error!("Upstream '{}' sent no response within {:?}", upstream.name, route.first_byte_timeout());
+            return Err(ProxyError::UpstreamTimeout);
+        }
+    };
+    if response.status().is_server_error() {
+        upstream.record_failure(&endpoint);
+    } else {
+        upstream.record_success(&endpoint);
+    }
+
+    let (mut parts, body) = Response::from(response).into_parts();
+    let hop_by_hop = headers::hop_by_hop_headers(parts.headers.get_all(CONNECTION).iter().map(|v| v.as_bytes()));
+    for name in hop_by_hop {
+        parts.headers.remove(name.as_str());
+    }
+    // The endpoint stays counted as active until the last frame, trailers included
+    let body = body
+        .map_frame(move |frame| {
+            let _active = &endpoint;
+            frame
+        })
+        .map_err(|e| Box::new(e) as Box<dyn StdError + Send + Sync>)
+        .boxed_unsync();
+    Ok(Response::from_parts(parts, body))
+}
+
+/// End-to-end headers from the client plus the proxy and identity headers,
+/// as for HTTP requests. gRPC servers also require `TE: trailers`, which is
+/// hop-by-hop but holds for the next hop as well.
+fn upstream_request_headers(
+    req: &Request<Incoming>,
+    connection: &Connection,
+    route: &Route,
+    api_key: &ApiKey,
+    request_id: &str,
+) -> HeaderMap {
+    let proto = if connection.secure { "https" } else { "http" };
+    let mut proxy_headers = headers::forwarded_headers(route.forwarded_headers, connection.peer.ip(), authority(req), proto, |name| {
+        req.headers().get(name).and_then(|v| v.to_str().ok())
+    });
+    proxy_headers.extend(headers::identity_headers(api_key));
+    proxy_headers.push((REQUEST_ID_HEADER, request_id.to_string()));
+
+    let hop_by_hop = headers::hop_by_hop_headers(req.headers().get_all(CONNECTION).iter().map(|v| v.as_bytes()));
+    let mut upstream_headers = HeaderMap::new();
+    for (name, value) in req.headers() {
+        if name == HOST
+            || hop_by_hop.contains(name.as_str())
+            || headers::CLIENT_ONLY_HEADERS.contains(&name.as_str())
+            || proxy_headers.iter().any(|(proxy_name, _)| name == proxy_name)
+        {
+            continue;
+        }
+        upstream_headers.append(name.clone(), value.clone());
+    }
+    for (name, value) in proxy_headers {
+        insert_header(&mut upstream_headers, name, &value);
+    }
+    upstream_headers.insert(TE, HeaderValue::from_static("trailers"));
+    upstream_headers
+}
+
+/// A trailers-only response carrying `error` as the call's status.
+fn status_response(error: ProxyError, message: &str) -> Response<ResponseBody> {
+    let mut response = Response::new(empty());
+    let headers = response.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+    headers.insert("grpc-status", HeaderValue::from(error.grpc_status()));
+    insert_header(headers, "grpc-message", &percent_encode(message));
+    response
+}
+
+/// Whether the request is a gRPC call, by `application/grpc` and its
+/// `+proto`, `+json` and similar variants.
+fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|content_type| {
+            content_type == "application/grpc"
+                || content_type.starts_with("application/grpc+")
+                || content_type.starts_with("application/grpc;")
+        })
+}
+
+/// The `:authority` of an HTTP/2 request, or its `Host` header.
+fn authority<B>(req: &Request<B>) -> Option<&str> {
+    req.uri()
+        .authority()
+        .map(|a| a.as_str())
+        .or_else(|| req.headers().get(HOST).and_then(|v| v.to_str().ok()))
+}
+
+/// Percent-encodes `grpc-message` as the gRPC HTTP/2 protocol requires:
+/// everything outside printable ASCII, and `%` itself.
+pub fn percent_encode(message: &str) -> String {
+    message
+        .bytes()
+        .map(|b| match b {
+            b' '..=b'~' if b != b'%' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn insert_header(headers: &mut HeaderMap, name: &'static str, value: &str) {
+    if let Ok(value) = HeaderValue::from_str(value) {
+        headers.insert(HeaderName::from_static(name), value);
+    }
+}
+
+fn empty() -> ResponseBody {
+    Empty::new().map_err(|never| match never {}).boxed_unsync()
+}
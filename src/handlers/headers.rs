@@ -0,0 +1,133 @@
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::net::IpAddr;
+use std::str::FromStr;
+use crate::db::ApiKey;
+
+/// Headers that only apply to a single connection (RFC 7230, section 6.1).
+const HOP_BY_HOP_HEADERS: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+/// Client headers never passed upstream: the customer's secret and the
+/// identity headers, which only the proxy may set.
+pub const CLIENT_ONLY_HEADERS: [&str; 4] = ["x-api-key", "x-consumer-id", "x-product-id", "x-user-id"];
+
+/// Which proxy headers are added to requests sent upstream.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForwardedHeaders {
+    None,
+    /// `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`.
+    XForwarded,
+    /// RFC 7239 `Forwarded`.
+    Forwarded,
+    Both,
+}
+
+impl ForwardedHeaders {
+    pub fn x_forwarded(self) -> bool {
+        matches!(self, ForwardedHeaders::XForwarded | ForwardedHeaders::Both)
+    }
+
+    pub fn forwarded(self) -> bool {
+        matches!(self, ForwardedHeaders::Forwarded | ForwardedHeaders::Both)
+    }
+}
+
+impl FromStr for ForwardedHeaders {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ForwardedHeaders::None),
+            "x_forwarded" => Ok(ForwardedHeaders::XForwarded),
+            "forwarded" => Ok(ForwardedHeaders::Forwarded),
+            "both" => Ok(ForwardedHeaders::Both),
+            _ => Err(format!("unknown forwarded headers mode '{}'", s)),
+        }
+    }
+}
+
+/// Lower-cased names of the headers that must not be forwarded: the standard
+/// hop-by-hop headers plus any listed in the message's `Connection` headers.
+pub fn hop_by_hop_headers<'a>(connection_values: impl Iterator<Item = &'a [u8]>) -> HashSet<String> {
+    let mut names: HashSet<String> = HOP_BY_HOP_HEADERS.iter().map(|name| name.to_string()).collect();
+    for value in connection_values {
+        for token in String::from_utf8_lossy(value).split(',') {
+            let token = token.trim();
+            if !token.is_empty() {
+                names.insert(token.to_ascii_lowercase());
+            }
+        }
+    }
+    names
+}
+
+/// Appends `client` to an existing `X-Forwarded-For` value.
+pub fn x_forwarded_for(existing: Option<&str>, client: IpAddr) -> String {
+    match existing {
+        Some(existing) if !existing.trim().is_empty() => format!("{}, {}", existing, client),
+        _ => client.to_string(),
+    }
+}
+
+/// Appends an element describing this hop to an existing `Forwarded` value.
+pub fn forwarded(existing: Option<&str>, client: IpAddr, host: Option<&str>, proto: &str) -> String {
+    let mut element = match client {
+        IpAddr::V4(ip) => format!("for={}", ip),
+        IpAddr::V6(ip) => format!("for=\"[{}]\"", ip),
+    };
+    if let Some(host) = host {
+        element.push_str(&format!(";host=\"{}\"", host.replace('"', "")));
+    }
+    element.push_str(&format!(";proto={}", proto));
+
+    match existing {
+        Some(existing) if !existing.trim().is_empty() => format!("{}, {}", existing, element),
+        _ => element,
+    }
+}
+
+/// The `X-Forwarded-*` and `Forwarded` headers selected by `mode` for the
+/// client's hop, extending the values `existing` returns for the request.
+pub fn forwarded_headers<'a>(
+    mode: ForwardedHeaders,
+    client_ip: IpAddr,
+    host: Option<&str>,
+    proto: &str,
+    existing: impl Fn(&str) -> Option<&'a str>,
+) -> Vec<(&'static str, String)> {
+    let mut proxy_headers = Vec::new();
+    if mode.x_forwarded() {
+        proxy_headers.push(("x-forwarded-for", x_forwarded_for(existing("x-forwarded-for"), client_ip)));
+        proxy_headers.push(("x-forwarded-proto", proto.to_string()));
+        if let Some(host) = host {
+            proxy_headers.push(("x-forwarded-host", host.to_string()));
+        }
+    }
+    if mode.forwarded() {
+        proxy_headers.push(("forwarded", forwarded(existing("forwarded"), client_ip, host, proto)));
+    }
+    proxy_headers
+}
+
+/// Headers telling the upstream which validated consumer sent the request,
+/// in place of the API key itself.
+pub fn identity_headers(api_key: &ApiKey) -> Vec<(&'static str, String)> {
+    let mut headers = vec![("x-consumer-id", api_key.id.to_string())];
+    if let Some(product_id) = api_key.product_id {
+        headers.push(("x-product-id", product_id.to_string()));
+    }
+    if let Some(user_id) = api_key.user_id {
+        headers.push(("x-user-id", user_id.to_string()));
+    }
+    headers
+}
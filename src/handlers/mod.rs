@@ -1,2 +1,28 @@
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Error};
+use std::sync::Arc;
+use crate::errors::ProxyError;
+use crate::routing::{Route, RouteKind};
+
+pub mod buffer;
+pub mod grpc;
+pub mod headers;
 pub mod regular;
-pub mod ws;
\ No newline at end of file
+pub mod sse;
+pub mod ws;
+
+/// Dispatches a request to the handler for the kind of route the middleware matched.
+pub async fn proxy(
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let route = req.extensions()
+        .get::<Arc<Route>>()
+        .cloned()
+        .ok_or(ProxyError::NoRoute)?;
+    match route.kind {
+        RouteKind::Http => regular::forward_request(req, payload, route).await,
+        RouteKind::Websocket => ws::ws_handler(req, payload, route).await,
+        // Only matched on the gRPC listener, which does not go through actix
+        RouteKind::Grpc => Err(ProxyError::NoRoute.into()),
+    }
+}
@@ -1,83 +1,348 @@
-use actix_web::{web, HttpRequest, HttpResponse, Error as ActixError};
-use reqwest::Client;
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Error as ActixError};
+use actix_web::body::{BodyStream, SizedStream};
+use actix_web::error::PayloadError;
+use actix_web::http::{header, Version};
+use futures::stream::LocalBoxStream;
+use futures::{FutureExt, SinkExt, StreamExt};
+use reqwest::header::{
+    HeaderMap as UpstreamHeaderMap, HeaderName as UpstreamHeaderName, HeaderValue as UpstreamHeaderValue,
+};
 use log::{error, debug};
 use std::sync::Arc;
 use std::error::Error as StdError;
-use crate::config::Config;
+use tokio::time::{sleep, timeout};
+use crate::db::ApiKey;
+use crate::errors::{ProxyError, RequestId, REQUEST_ID_HEADER};
+use crate::retry;
+use crate::routing::Route;
+use super::headers::{self, ForwardedHeaders};
+use super::sse;
+
+/// Number of request body chunks buffered between the client and the upstream.
+const BODY_CHANNEL_CAPACITY: usize = 8;
 
 pub async fn forward_request(
     req: HttpRequest,
-    body: web::Bytes,
-    client: web::Data<Arc<Client>>,
-    config: web::Data<Arc<Config>>
+    payload: web::Payload,
+    route: Arc<Route>,
 ) -> Result<HttpResponse, ActixError> {
-    let path = req.uri().path().trim_start_matches("/api/v1");
-    let connection_info = req.connection_info().clone();
-    let scheme = connection_info.scheme();
-
-    let target_url = match scheme {
-        "http" => &config.target_http_url,
-        "https" => &config.target_https_url,
-        _ => return Ok(HttpResponse::BadRequest().body("Unsupported scheme")),
-    };
-
-    let new_url = format!("{}{}", target_url, path);
-    
-    debug!("Forwarding to URL: {}", new_url);
+    let upstream = &route.upstream;
+    let api_key_id = req.extensions().get::<ApiKey>().map(|k| k.id);
     debug!("Original request method: {}", req.method());
 
     // Convert actix_web::http::Method to reqwest::Method
     let method = match reqwest::Method::from_bytes(req.method().as_str().as_bytes()) {
         Ok(m) => m,
-        Err(_) => return Ok(HttpResponse::MethodNotAllowed().finish()),
+        Err(_) => return Err(ProxyError::MethodNotAllowed.into()),
     };
+    let upstream_headers = upstream_request_headers(&req, &route);
 
-    let mut forwarded_req = client.request(method, &new_url);
+    // Event streams count against the key's limit until they close. Clients
+    // asking for one are held to it before anything is sent upstream.
+    let mut stream_guard = None;
+    if sse::accepts_event_stream(req.headers().get_all(header::ACCEPT).filter_map(|v| v.to_str().ok())) {
+        stream_guard = Some(acquire_stream(&route, api_key_id)?);
+    }
 
-    // Forward headers
-    for (name, value) in req.headers() {
-        if name != "host" {  // Don't forward the Host header
-            forwarded_req = forwarded_req.header(name.as_str(), value.as_bytes());
-            debug!("Forwarding header: {}={:?}", name, value);
-        }
+    // Only requests that are safe to send twice, and whose body can be sent again, are retried
+    let retry = route.retry.as_ref().filter(|policy| {
+        retry::is_idempotent(req.method().as_str())
+            || req.headers().contains_key(policy.config.idempotency_header.as_str())
+    });
+    let mut body = RequestBody::new(&req, payload, retry.map(|p| p.config.max_buffered_body_bytes)).await?;
+    let retry = retry.filter(|_| body.is_replayable());
+    if let Some(policy) = retry {
+        policy.record_request();
     }
 
-    // Send the request
-    match forwarded_req.body(body).send().await {
+    let mut retries = 0;
+    let (endpoint, result) = loop {
+        let endpoint = match upstream.select(api_key_id) {
+            Ok(endpoint) => endpoint,
+            Err(e) => {
+                error!("Upstream '{}' unavailable: {}", upstream.name, e);
+                return Err(ProxyError::from(e).into());
+            }
+        };
+        let new_url = route.upstream_url(&endpoint, req.uri().path(), req.uri().query());
+        debug!("Forwarding to URL: {} (route '{}')", new_url, route.name);
+
+        let mut forwarded_req = route.client(&endpoint)
+            .request(method.clone(), &new_url)
+            .headers(upstream_headers.clone());
+        if let Some(total) = route.total_timeout() {
+            forwarded_req = forwarded_req.timeout(total);
+        }
+        if let Some(body) = body.take() {
+            forwarded_req = forwarded_req.body(body);
+        }
+
+        // Send the request, waiting at most the first-byte timeout for the response headers
+        let result = match timeout(route.first_byte_timeout(), forwarded_req.send()).await {
+            Ok(result) => result.map_err(UpstreamError::Request),
+            Err(_) => Err(UpstreamError::Timeout),
+        };
+        let status = result.as_ref().ok().map(|response| response.status().as_u16());
+        if status.is_none_or(|status| status >= 500) {
+            upstream.record_failure(&endpoint);
+        } else {
+            upstream.record_success(&endpoint);
+        }
+
+        match retry {
+            Some(policy)
+                if status.is_none_or(|status| policy.retries_status(status))
+                    && retries < policy.config.max_retries
+                    && policy.try_retry() =>
+            {
+                retries += 1;
+                let backoff = policy.backoff(retries);
+                debug!("Retrying request on route '{}' in {:?} (retry {})", route.name, backoff, retries);
+                sleep(backoff).await;
+            }
+            _ => break (endpoint, result),
+        }
+    };
+
+    match result {
         Ok(response) => {
+            // Also counts event streams sent to clients that did not ask for one
+            let stream_guard = match stream_guard {
+                _ if !sse::is_event_stream(response.headers()) => None,
+                Some(guard) => Some(guard),
+                None => Some(acquire_stream(&route, api_key_id)?),
+            };
+
             // Convert reqwest::StatusCode to actix_web::http::StatusCode
             let status = actix_web::http::StatusCode::from_u16(response.status().as_u16())
                 .unwrap_or(actix_web::http::StatusCode::INTERNAL_SERVER_ERROR);
             
             let mut client_resp = HttpResponse::build(status);
             
-            // Forward response headers
+            // Forward end-to-end response headers; body framing is left to actix
+            let hop_by_hop = headers::hop_by_hop_headers(
+                response.headers().get_all(reqwest::header::CONNECTION).iter().map(|v| v.as_bytes()),
+            );
             for (name, value) in response.headers() {
+                if name == reqwest::header::CONTENT_LENGTH || hop_by_hop.contains(name.as_str()) {
+                    continue;
+                }
                 client_resp.append_header((name.as_str(), value.as_bytes()));
                 debug!("Forwarding response header: {}={:?}", name, value);
             }
 
-            // Forward response body
-            match response.bytes().await {
-                Ok(body) => {
-                    debug!("Forwarding response body of size: {} bytes", body.len());
-                    Ok(client_resp.body(body))
-                },
-                Err(e) => {
+            // Stream the response body back as it arrives, keeping the
+            // endpoint counted as active until the body is done
+            let content_length = response.content_length();
+            let has_cache_control = response.headers().contains_key(reqwest::header::CACHE_CONTROL);
+            let body = response.bytes_stream().map(move |chunk| {
+                let _active = &endpoint;
+                chunk.map_err(|e| {
                     error!("Failed to read response body: {:?}", e);
-                    Ok(HttpResponse::InternalServerError().finish())
+                    e
+                })
+            });
+
+            // Events go out chunk by chunk as they arrive; ask intermediaries
+            // not to buffer them either
+            if let Some(stream_guard) = stream_guard {
+                if !has_cache_control {
+                    client_resp.insert_header((header::CACHE_CONTROL, "no-cache"));
                 }
+                client_resp.insert_header(("x-accel-buffering", "no"));
+                let body = body.map(move |chunk| {
+                    let _stream = &stream_guard;
+                    chunk
+                });
+                return Ok(client_resp.body(BodyStream::new(sse::with_idle_timeout(body, route.sse_idle_timeout()))));
             }
-        },
-        Err(e) => {
-            error!("Failed to forward request: {:?}", e);
-            if let Some(url) = e.url() {
-                error!("Failed URL: {}", url);
+
+            match content_length {
+                Some(length) => {
+                    debug!("Streaming response body of size: {} bytes", length);
+                    Ok(client_resp.body(SizedStream::new(length, body)))
+                }
+                None => Ok(client_resp.body(BodyStream::new(body))),
             }
+        },
+        Err(UpstreamError::Timeout) => {
+            error!("Upstream '{}' sent no response within {:?}", upstream.name, route.first_byte_timeout());
+            Err(ProxyError::UpstreamTimeout.into())
+        }
+        Err(UpstreamError::Request(e)) => {
+            error!("Failed to forward request to upstream '{}': {:?}", upstream.name, e);
             if let Some(source) = e.source() {
                 error!("Error source: {:?}", source);
             }
-            Ok(HttpResponse::BadGateway().body(format!("Failed to forward request: {:?}", e)))
+            let error = if e.is_timeout() {
+                ProxyError::UpstreamTimeout
+            } else if e.is_connect() {
+                ProxyError::UpstreamUnreachable
+            } else {
+                ProxyError::BadGateway
+            };
+            Err(error.into())
+        }
+    }
+}
+
+fn acquire_stream(route: &Route, api_key_id: Option<i32>) -> Result<sse::StreamGuard, ProxyError> {
+    route.streams().acquire(api_key_id).ok_or_else(|| {
+        debug!("Too many event streams on route '{}' for API key {:?}", route.name, api_key_id);
+        ProxyError::TooManyStreams
+    })
+}
+
+enum UpstreamError {
+    /// No response headers within the route's first-byte timeout.
+    Timeout,
+    Request(reqwest::Error),
+}
+
+/// End-to-end headers from the client, without the proxy and identity
+/// headers we set ourselves, followed by those headers.
+fn upstream_request_headers(req: &HttpRequest, route: &Route) -> UpstreamHeaderMap {
+    let mut upstream_headers = UpstreamHeaderMap::new();
+    let mut proxy_headers = forwarded_headers(req, route.forwarded_headers);
+    if let Some(api_key) = req.extensions().get::<ApiKey>() {
+        proxy_headers.extend(headers::identity_headers(api_key));
+    }
+    if let Some(RequestId(request_id)) = req.extensions().get::<RequestId>() {
+        proxy_headers.push((REQUEST_ID_HEADER, request_id.clone()));
+    }
+    let hop_by_hop = headers::hop_by_hop_headers(
+        req.headers().get_all(header::CONNECTION).map(|v| v.as_bytes()),
+    );
+    for (name, value) in req.headers() {
+        if name == header::HOST  // Don't forward the Host header
+            || hop_by_hop.contains(name.as_str())
+            || headers::CLIENT_ONLY_HEADERS.contains(&name.as_str())
+            || proxy_headers.iter().any(|(proxy_name, _)| name == proxy_name)
+        {
+            continue;
+        }
+        if let (Ok(name), Ok(value)) = (
+            UpstreamHeaderName::from_bytes(name.as_str().as_bytes()),
+            UpstreamHeaderValue::from_bytes(value.as_bytes()),
+        ) {
+            debug!("Forwarding header: {}={:?}", name, value);
+            upstream_headers.append(name, value);
+        }
+    }
+    for (name, value) in proxy_headers {
+        debug!("Adding proxy header: {}={}", name, value);
+        if let Ok(value) = UpstreamHeaderValue::from_str(&value) {
+            upstream_headers.insert(UpstreamHeaderName::from_static(name), value);
+        }
+    }
+    // Compressed events can't be passed on one at a time
+    if sse::accepts_event_stream(req.headers().get_all(header::ACCEPT).filter_map(|v| v.to_str().ok())) {
+        upstream_headers.insert(reqwest::header::ACCEPT_ENCODING, UpstreamHeaderValue::from_static("identity"));
+    }
+    upstream_headers
+}
+
+/// The client's request body as sent upstream.
+enum RequestBody {
+    Empty,
+    /// Read in full so it can be sent again on retry.
+    Buffered(web::Bytes),
+    /// Streamed through once; taken by the first attempt.
+    Streamed(Option<LocalBoxStream<'static, Result<web::Bytes, PayloadError>>>),
+}
+
+impl RequestBody {
+    /// Buffers bodies whose `Content-Length` is within `buffer_limit`, if any,
+    /// and streams the rest.
+    async fn new(req: &HttpRequest, mut payload: web::Payload, buffer_limit: Option<usize>) -> Result<Self, ActixError> {
+        let mut first = None;
+        if req.version() == Version::HTTP_2 && content_length(req).is_none() {
+            // HTTP/2 has no `Transfer-Encoding`: a body follows unless the
+            // headers ended the stream, which leaves the payload finished.
+            match payload.next().now_or_never() {
+                Some(None) => return Ok(RequestBody::Empty),
+                Some(Some(chunk)) => first = Some(chunk),
+                None => {}
+            }
+        } else if !has_body(req) {
+            return Ok(RequestBody::Empty);
+        }
+        match (content_length(req), buffer_limit) {
+            (Some(length), Some(limit)) if length <= limit as u64 => {
+                let mut buffer = web::BytesMut::with_capacity(length as usize);
+                while let Some(chunk) = payload.next().await {
+                    buffer.extend_from_slice(&chunk.map_err(|e| {
+                        error!("Failed to read request body: {}", e);
+                        ProxyError::BadRequest
+                    })?);
+                }
+                Ok(RequestBody::Buffered(buffer.freeze()))
+            }
+            _ => Ok(RequestBody::Streamed(Some(futures::stream::iter(first).chain(payload).boxed_local()))),
         }
     }
-}
\ No newline at end of file
+
+    fn is_replayable(&self) -> bool {
+        !matches!(self, RequestBody::Streamed(_))
+    }
+
+    fn take(&mut self) -> Option<reqwest::Body> {
+        match self {
+            RequestBody::Empty => None,
+            RequestBody::Buffered(bytes) => Some(bytes.clone().into()),
+            RequestBody::Streamed(payload) => payload.take().map(stream_payload),
+        }
+    }
+}
+
+/// The `X-Forwarded-*` and `Forwarded` headers describing the client's hop,
+/// extending whatever the client already sent.
+fn forwarded_headers(req: &HttpRequest, mode: ForwardedHeaders) -> Vec<(&'static str, String)> {
+    let client_ip = match req.peer_addr() {
+        Some(addr) => addr.ip(),
+        None => return Vec::new(),
+    };
+    let proto = if req.app_config().secure() { "https" } else { "http" };
+    let host = req.headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| req.uri().authority().map(|a| a.as_str()));
+    headers::forwarded_headers(mode, client_ip, host, proto, |name| {
+        req.headers().get(name).and_then(|v| v.to_str().ok())
+    })
+}
+
+fn content_length(req: &HttpRequest) -> Option<u64> {
+    req.headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok())
+}
+
+/// Whether the client announced a request body, either with a non-zero
+/// `Content-Length` or with `Transfer-Encoding`.
+fn has_body(req: &HttpRequest) -> bool {
+    match content_length(req) {
+        Some(length) => length > 0,
+        None => req.headers().contains_key(header::TRANSFER_ENCODING),
+    }
+}
+
+/// Turns the client's payload into a reqwest body. The payload is not `Send`,
+/// so it is pumped from a local task through a bounded channel, which also
+/// stops reading from the client while the upstream is not consuming.
+fn stream_payload(mut payload: LocalBoxStream<'static, Result<web::Bytes, PayloadError>>) -> reqwest::Body {
+    let (mut tx, rx) = futures::channel::mpsc::channel::<Result<web::Bytes, std::io::Error>>(BODY_CHANNEL_CAPACITY);
+
+    actix_web::rt::spawn(async move {
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| std::io::Error::other(e.to_string()));
+            let failed = chunk.is_err();
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    reqwest::Body::wrap_stream(rx)
+}
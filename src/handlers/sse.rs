@@ -0,0 +1,90 @@
+use futures::{Stream, StreamExt};
+use log::debug;
+use reqwest::header::{HeaderMap, CONTENT_TYPE};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::timeout;
+
+const EVENT_STREAM: &str = "text/event-stream";
+
+/// Whether an upstream response is a server-sent event stream.
+pub fn is_event_stream(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|content_type| content_type.trim_start().to_ascii_lowercase().starts_with(EVENT_STREAM))
+}
+
+/// Whether a client's `Accept` values ask for a server-sent event stream.
+pub fn accepts_event_stream<'a>(accept: impl IntoIterator<Item = &'a str>) -> bool {
+    accept
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .any(|media_type| media_type.trim().to_ascii_lowercase().starts_with(EVENT_STREAM))
+}
+
+/// Open event streams per API key on a route.
+#[derive(Debug)]
+pub struct StreamLimiter {
+    /// Streams allowed per key; 0 for no limit.
+    max: u32,
+    open: Mutex<HashMap<Option<i32>, u32>>,
+}
+
+impl StreamLimiter {
+    pub fn new(max: u32) -> Self {
+        StreamLimiter { max, open: Mutex::new(HashMap::new()) }
+    }
+
+    /// Counts a new stream for `api_key_id`, or returns `None` when the key
+    /// already has the maximum open. The stream is counted until the guard
+    /// is dropped.
+    pub fn acquire(self: &Arc<Self>, api_key_id: Option<i32>) -> Option<StreamGuard> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(api_key_id).or_insert(0);
+        if self.max > 0 && *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        Some(StreamGuard { limiter: Arc::clone(self), api_key_id })
+    }
+
+    /// Streams currently open for `api_key_id`.
+    pub fn open(&self, api_key_id: Option<i32>) -> u32 {
+        self.open.lock().unwrap().get(&api_key_id).copied().unwrap_or(0)
+    }
+}
+
+/// An open event stream, counted against its API key until dropped.
+#[derive(Debug)]
+pub struct StreamGuard {
+    limiter: Arc<StreamLimiter>,
+    api_key_id: Option<i32>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.api_key_id) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.api_key_id);
+            }
+        }
+    }
+}
+
+/// Ends `stream` once no item arrives for `idle`. Keep-alive comments sent
+/// by the upstream count as activity.
+pub fn with_idle_timeout<S: Stream>(stream: S, idle: Duration) -> impl Stream<Item = S::Item> {
+    futures::stream::unfold(Box::pin(stream), move |mut stream| async move {
+        match timeout(idle, stream.next()).await {
+            Ok(item) => item.map(|item| (item, stream)),
+            Err(_) => {
+                debug!("Closing event stream idle for {:?}", idle);
+                None
+            }
+        }
+    })
+}
@@ -1,109 +1,277 @@
-use actix_web::{web, HttpRequest, HttpResponse, Error};
+use actix_web::{web, HttpMessage, HttpRequest, HttpResponse, Error};
+use actix_web::error::PayloadError;
 use actix_web_actors::ws;
 use log::{error, debug};
+use std::collections::VecDeque;
 use std::sync::Arc;
-use tokio_tungstenite::{connect_async, tungstenite::Message as TungsteniteMessage};
-use futures::{StreamExt, SinkExt};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::timeout;
+use tokio_tungstenite::{client_async_tls_with_config, Connector, MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::http::{HeaderName, HeaderValue};
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+use tokio_tungstenite::tungstenite::Message as TungsteniteMessage;
+use futures::{Stream, StreamExt, SinkExt};
 use actix::prelude::*;
-use crate::config::Config;
+use crate::config::WebSocketConfig;
+use crate::db::ApiKey;
+use crate::errors::{ProxyError, RequestId, REQUEST_ID_HEADER};
+use crate::routing::Route;
+use crate::upstream::{Endpoint, EndpointGuard, Upstream};
+use super::buffer::{MessageBuffer, PushError};
+use super::headers;
+
+/// Opens the handshake to `endpoint`, whose URL host may differ from the
+/// request's when the upstream sets a TLS `server_name`.
+async fn connect(
+    request: Request,
+    endpoint: &Endpoint,
+    upstream: &Upstream,
+) -> Result<UpstreamConnection, String> {
+    let stream = TcpStream::connect((endpoint.host.as_str(), endpoint.port))
+        .await
+        .map_err(|e| e.to_string())?;
+    let connector = Some(Connector::Rustls(Arc::clone(&upstream.tls)));
+    let (ws_stream, _) = client_async_tls_with_config(request, stream, None, connector)
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(ws_stream)
+}
+
+type UpstreamConnection = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Close code for a target that could not be reached or went away: 1011
+/// (internal error), as not every client accepts 1014 (bad gateway).
+const UPSTREAM_ERROR: u16 = 1011;
+
+fn close_message(code: u16, reason: &str) -> TungsteniteMessage {
+    TungsteniteMessage::Close(Some(CloseFrame { code: CloseCode::from(code), reason: reason.to_string().into() }))
+}
+
+/// Connects to the target, sends it the messages queued for it so far and
+/// then relays messages both ways until either side is done. The client is
+/// sent a close frame if the target can't be reached.
+async fn relay(
+    request: Request,
+    endpoint: Arc<Endpoint>,
+    upstream: Arc<Upstream>,
+    handshake_timeout: Duration,
+    config: WebSocketConfig,
+    target_buffer: Arc<MessageBuffer<TungsteniteMessage>>,
+    client_buffer: Arc<MessageBuffer<TungsteniteMessage>>,
+) {
+    let connected = match timeout(handshake_timeout, connect(request, &endpoint, &upstream)).await {
+        Ok(result) => result,
+        Err(_) => Err(format!("no handshake response within {:?}", handshake_timeout)),
+    };
+    let ws_stream = match connected {
+        Ok(ws_stream) => {
+            upstream.record_success(&endpoint);
+            debug!("Connected to target WebSocket: {} ({} messages queued)", endpoint.url, target_buffer.len());
+            ws_stream
+        }
+        Err(e) => {
+            upstream.record_failure(&endpoint);
+            error!("Failed to connect to target WebSocket: {}", e);
+            target_buffer.close();
+            client_buffer.close_with(close_message(UPSTREAM_ERROR, "Upstream unavailable"));
+            return;
+        }
+    };
+
+    let (mut write, mut read) = ws_stream.split();
+    let writer = async {
+        while let Some(msg) = target_buffer.pop().await {
+            if write.send(msg).await.is_err() {
+                return;
+            }
+        }
+        let _ = write.close().await;
+    };
+    let reader = async {
+        while let Some(message) = read.next().await {
+            match message {
+                Ok(msg) => {
+                    debug!("Received message from target: {:?}", msg);
+                    client_buffer.push(msg).await?;
+                }
+                Err(e) => error!("Error receiving message from target: {}", e),
+            }
+        }
+        Ok(())
+    };
+    let result = tokio::select! {
+        _ = writer => Ok(()),
+        result = reader => result,
+    };
+
+    target_buffer.close();
+    if result == Err(PushError::Full) {
+        error!("WebSocket client buffer full for {}, disconnecting", endpoint.url);
+        client_buffer.close_with(close_message(config.close_code, "Message buffer full"));
+    } else {
+        // The session stops once the client has been sent what is left
+        client_buffer.close();
+    }
+}
 
 struct WebSocketSession {
-    target_url: String,
-    target_tx: Option<futures::channel::mpsc::UnboundedSender<TungsteniteMessage>>,
+    /// Held for the lifetime of the session so it counts as an active connection.
+    endpoint: EndpointGuard,
+    upstream: Arc<Upstream>,
+    handshake_timeout: Duration,
+    config: WebSocketConfig,
+    request: Option<Request>,
+    /// Messages from the target, drained into the client connection.
+    client_buffer: Arc<MessageBuffer<TungsteniteMessage>>,
+    /// Messages for the target, queued from the start and sent once it is connected.
+    target_buffer: Arc<MessageBuffer<TungsteniteMessage>>,
+    /// Client messages waiting for their turn to be queued for the target,
+    /// already read while an earlier one waits for room.
+    held: VecDeque<TungsteniteMessage>,
+    /// Set while a message waits for room, which pauses reading from the client.
+    client_paused: watch::Sender<bool>,
+    /// Whether the client has been sent a close frame.
+    close_sent: bool,
 }
 
 impl WebSocketSession {
-    fn new(config: &Config, original_path: &str) -> Self {
-        let target_url = format!("{}{}", config.target_ws_url, original_path);
+    fn new(target_url: String, route: &Route, endpoint: EndpointGuard, upstream_headers: Vec<(&'static str, String)>) -> Result<Self, Error> {
         debug!("Creating WebSocketSession with target URL: {}", target_url);
-        WebSocketSession {
-            target_url,
-            target_tx: None,
+
+        // The handshake request sent to the target, carrying the identity and request id headers
+        let mut request = target_url.as_str().into_client_request().map_err(|e| {
+            error!("Invalid target WebSocket URL {}: {}", target_url, e);
+            ProxyError::BadGateway
+        })?;
+        for (name, value) in upstream_headers {
+            if let Ok(value) = HeaderValue::from_str(&value) {
+                request.headers_mut().insert(HeaderName::from_static(name), value);
+            }
         }
+
+        let config = route.websocket;
+        Ok(WebSocketSession {
+            endpoint,
+            upstream: Arc::clone(&route.upstream),
+            handshake_timeout: Duration::from_millis(route.timeouts.connect_ms + route.timeouts.first_byte_ms),
+            config,
+            request: Some(request),
+            client_buffer: Arc::new(MessageBuffer::new(config.client_buffer, config.overflow)),
+            target_buffer: Arc::new(MessageBuffer::new(config.upstream_buffer, config.overflow)),
+            held: VecDeque::new(),
+            client_paused: watch::Sender::new(false),
+            close_sent: false,
+        })
     }
-}
 
-impl Actor for WebSocketSession {
-    type Context = ws::WebsocketContext<Self>;
+    /// Queues a client message for the target, in order. Under the `block`
+    /// policy the session reads nothing more from the client until there is
+    /// room, while messages from the target keep being delivered.
+    fn forward(&mut self, msg: TungsteniteMessage, ctx: &mut <Self as Actor>::Context) {
+        self.held.push_back(msg);
+        if !*self.client_paused.borrow() {
+            self.forward_held(ctx);
+        }
+    }
 
-    fn started(&mut self, ctx: &mut Self::Context) {
-        let target_url = self.target_url.clone();
-        let addr = ctx.address();
-        debug!("WebSocketSession started, connecting to: {}", target_url);
+    /// Queues the oldest held message, then the next one once it is queued,
+    /// resuming reads from the client when none are left.
+    fn forward_held(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let msg = match self.held.pop_front() {
+            Some(msg) => msg,
+            None => {
+                self.client_paused.send_replace(false);
+                return;
+            }
+        };
+        self.client_paused.send_replace(true);
+        let target_buffer = Arc::clone(&self.target_buffer);
+        let closing = matches!(msg, TungsteniteMessage::Close(_));
         ctx.spawn(
-            async move {
-                match connect_async(&target_url).await {
-                    Ok((ws_stream, _)) => {
-                        debug!("Connected to target WebSocket: {}", target_url);
-                        let (mut write, mut read) = ws_stream.split();
-                        let (tx, mut rx) = futures::channel::mpsc::unbounded();
-                        
-                        addr.do_send(SetTargetTx(tx));
-
-                        tokio::spawn(async move {
-                            while let Some(msg) = rx.next().await {
-                                if write.send(msg).await.is_err() {
-                                    break;
-                                }
-                            }
-                        });
-
-                        while let Some(message) = read.next().await {
-                            match message {
-                                Ok(msg) => {
-                                    debug!("Received message from target: {:?}", msg);
-                                    addr.do_send(ForwardMessage(msg));
-                                }
-                                Err(e) => error!("Error receiving message from target: {}", e),
-                            }
-                        }
+            async move { target_buffer.push(msg).await }
+                .into_actor(self)
+                .map(move |result, act, ctx| {
+                    if result == Err(PushError::Full) {
+                        error!("WebSocket upstream buffer full for {}, disconnecting", act.endpoint.url);
+                        act.close(ws::CloseCode::from(act.config.close_code), "Message buffer full", ctx);
+                        ctx.stop();
+                    } else if closing {
+                        ctx.stop();
+                    } else {
+                        act.forward_held(ctx);
                     }
-                    Err(e) => error!("Failed to connect to target WebSocket: {}", e),
-                }
-            }
-            .into_actor(self)
+                }),
         );
     }
+
+    fn close(&mut self, code: ws::CloseCode, reason: &str, ctx: &mut <Self as Actor>::Context) {
+        if !self.close_sent {
+            self.close_sent = true;
+            ctx.close(Some(ws::CloseReason { code, description: Some(reason.to_string()) }));
+        }
+    }
 }
 
-#[derive(Message)]
-#[rtype(result = "()")]
-struct SetTargetTx(futures::channel::mpsc::UnboundedSender<TungsteniteMessage>);
+impl Actor for WebSocketSession {
+    type Context = ws::WebsocketContext<Self>;
 
-impl Handler<SetTargetTx> for WebSocketSession {
-    type Result = ();
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.add_stream(MessageBuffer::into_stream(Arc::clone(&self.client_buffer)));
+        let request = match self.request.take() {
+            Some(request) => request,
+            None => return,
+        };
+        debug!("WebSocketSession started, connecting to: {}", request.uri());
+        tokio::spawn(relay(
+            request,
+            Arc::clone(self.endpoint.endpoint()),
+            Arc::clone(&self.upstream),
+            self.handshake_timeout,
+            self.config,
+            Arc::clone(&self.target_buffer),
+            Arc::clone(&self.client_buffer),
+        ));
+    }
 
-    fn handle(&mut self, msg: SetTargetTx, _: &mut Self::Context) {
-        self.target_tx = Some(msg.0);
+    fn stopped(&mut self, _: &mut Self::Context) {
+        // The relay sends the target what is already queued, then ends
+        self.client_buffer.close();
+        self.target_buffer.close();
     }
 }
 
-#[derive(Message)]
-#[rtype(result = "()")]
-struct ForwardMessage(TungsteniteMessage);
-
-impl Handler<ForwardMessage> for WebSocketSession {
-    type Result = ();
-
-    fn handle(&mut self, msg: ForwardMessage, ctx: &mut Self::Context) {
-        match msg.0 {
+/// Messages from the target, in order, as the client connection takes them.
+impl StreamHandler<TungsteniteMessage> for WebSocketSession {
+    fn handle(&mut self, msg: TungsteniteMessage, ctx: &mut Self::Context) {
+        match msg {
             TungsteniteMessage::Text(text) => ctx.text(text),
             TungsteniteMessage::Binary(bin) => ctx.binary(bin),
             TungsteniteMessage::Ping(data) => ctx.ping(&data),
             TungsteniteMessage::Pong(data) => ctx.pong(&data),
-            TungsteniteMessage::Close(reason) => {
-                if let Some(frame) = reason {
-                    ctx.close(Some(ws::CloseReason {
-                        code: ws::CloseCode::from(u16::from(frame.code)),
-                        description: Some(frame.reason.into_owned()),
-                    }));
-                } else {
+            TungsteniteMessage::Close(Some(frame)) => {
+                self.close(ws::CloseCode::from(u16::from(frame.code)), &frame.reason, ctx);
+            }
+            TungsteniteMessage::Close(None) => {
+                if !self.close_sent {
+                    self.close_sent = true;
                     ctx.close(None);
                 }
-            },
+            }
             TungsteniteMessage::Frame(_) => {}
         }
     }
+
+    /// The target is done: closes the client connection too, if the target
+    /// went away without a close frame.
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        self.close(ws::CloseCode::from(UPSTREAM_ERROR), "Upstream connection closed", ctx);
+        ctx.stop();
+    }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession {
@@ -112,26 +280,20 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession
             Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
             Ok(ws::Message::Text(text)) => {
                 debug!("Received text message from client: {}", text);
-                if let Some(tx) = &self.target_tx {
-                    let _ = tx.unbounded_send(TungsteniteMessage::Text(text.to_string()));
-                }
+                self.forward(TungsteniteMessage::Text(text.to_string()), ctx);
             }
             Ok(ws::Message::Binary(bin)) => {
                 debug!("Received binary message from client: {} bytes", bin.len());
-                if let Some(tx) = &self.target_tx {
-                    let _ = tx.unbounded_send(TungsteniteMessage::Binary(bin.to_vec()));
-                }
+                self.forward(TungsteniteMessage::Binary(bin.to_vec()), ctx);
             }
             Ok(ws::Message::Close(reason)) => {
                 debug!("Client closed connection: {:?}", reason);
-                if let Some(tx) = &self.target_tx {
-                    let close_frame = reason.map(|r| tokio_tungstenite::tungstenite::protocol::CloseFrame {
-                        code: tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode::from(u16::from(r.code)),
-                        reason: r.description.unwrap_or_default().into(),
-                    });
-                    let _ = tx.unbounded_send(TungsteniteMessage::Close(close_frame));
-                }
-                ctx.stop();
+                let close_frame = reason.map(|r| CloseFrame {
+                    code: CloseCode::from(u16::from(r.code)),
+                    reason: r.description.unwrap_or_default().into(),
+                });
+                // Stops the session once the close frame is queued
+                self.forward(TungsteniteMessage::Close(close_frame), ctx);
             }
             _ => (),
         }
@@ -141,10 +303,39 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketSession
 pub async fn ws_handler(
     req: HttpRequest,
     stream: web::Payload,
-    config: web::Data<Arc<Config>>,
+    route: Arc<Route>,
 ) -> Result<HttpResponse, Error> {
-    let path = req.uri().path().to_owned();
-    debug!("WebSocket handler called with path: {}", path);
-    let session = WebSocketSession::new(&config, &path);
-    ws::start(session, &req, stream)
+    debug!("WebSocket handler called with path: {} (route '{}')", req.path(), route.name);
+    let (api_key_id, mut upstream_headers) = match req.extensions().get::<ApiKey>() {
+        Some(api_key) => (Some(api_key.id), headers::identity_headers(api_key)),
+        None => (None, Vec::new()),
+    };
+    if let Some(RequestId(request_id)) = req.extensions().get::<RequestId>() {
+        upstream_headers.push((REQUEST_ID_HEADER, request_id.clone()));
+    }
+    let endpoint = match route.upstream.select(api_key_id) {
+        Ok(endpoint) => endpoint,
+        Err(e) => {
+            error!("Upstream '{}' unavailable: {}", route.upstream.name, e);
+            return Err(ProxyError::from(e).into());
+        }
+    };
+    let target_url = route.upstream_url(&endpoint, req.uri().path(), req.uri().query());
+    let session = WebSocketSession::new(target_url, &route, endpoint, upstream_headers)?;
+    let client_paused = session.client_paused.subscribe();
+    ws::start(session, &req, paused_while(stream, client_paused))
+}
+
+/// Reads `payload` only while `paused` is unset. Frames already read when it
+/// is set are still decoded, and held by the session.
+fn paused_while(
+    payload: web::Payload,
+    paused: watch::Receiver<bool>,
+) -> impl Stream<Item = Result<web::Bytes, PayloadError>> {
+    futures::stream::unfold((payload, paused), |(mut payload, mut paused)| async move {
+        // An error means the session is gone, and with it any reason to wait
+        let _ = paused.wait_for(|paused| !paused).await;
+        let chunk = payload.next().await?;
+        Some((chunk, (payload, paused)))
+    })
 }
\ No newline at end of file
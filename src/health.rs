@@ -0,0 +1,160 @@
+use futures::future::join_all;
+use log::{debug, error, info, warn};
+use reqwest::Client;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::time::interval;
+use crate::config::{HealthCheckConfig, PassiveHealthCheckConfig};
+use crate::upstream::{Endpoint, Upstream};
+
+/// Health of one endpoint, as seen by active probes and by live traffic.
+#[derive(Debug)]
+pub struct EndpointHealth {
+    passing: AtomicBool,
+    ejected: AtomicBool,
+    ejected_until: Mutex<Option<Instant>>,
+    failures: AtomicU32,
+    passive: Option<PassiveHealthCheckConfig>,
+}
+
+impl EndpointHealth {
+    pub fn new(passive: Option<PassiveHealthCheckConfig>) -> Self {
+        EndpointHealth {
+            passing: AtomicBool::new(true),
+            ejected: AtomicBool::new(false),
+            ejected_until: Mutex::new(None),
+            failures: AtomicU32::new(0),
+            passive,
+        }
+    }
+}
+
+impl Endpoint {
+    /// Whether the endpoint passes its probes and is not ejected. Ejections
+    /// that have run out are lifted here.
+    pub fn is_available(&self) -> bool {
+        let health = &self.health;
+        if !health.passing.load(Ordering::Relaxed) {
+            return false;
+        }
+        if !health.ejected.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        let mut ejected_until = health.ejected_until.lock().unwrap();
+        match *ejected_until {
+            Some(until) if Instant::now() < until => false,
+            _ => {
+                *ejected_until = None;
+                if health.ejected.swap(false, Ordering::Relaxed) {
+                    info!("Reinstating upstream endpoint {} after ejection", self.url);
+                }
+                true
+            }
+        }
+    }
+
+    /// Records a request the endpoint answered without a `5xx`.
+    pub fn record_success(&self) {
+        self.health.failures.store(0, Ordering::Relaxed);
+    }
+
+    /// Records a connection error, timeout or `5xx`, ejecting the endpoint
+    /// once `max_failures` happen in a row.
+    pub fn record_failure(&self) {
+        let passive = match self.health.passive {
+            Some(passive) => passive,
+            None => return,
+        };
+        let failures = self.health.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures < passive.max_failures.max(1) {
+            return;
+        }
+
+        self.health.failures.store(0, Ordering::Relaxed);
+        let ejection = Duration::from_secs(passive.ejection_seconds);
+        *self.health.ejected_until.lock().unwrap() = Some(Instant::now() + ejection);
+        if !self.health.ejected.swap(true, Ordering::Relaxed) {
+            warn!("Ejecting upstream endpoint {} for {:?} after {} consecutive failures", self.url, ejection, failures);
+        }
+    }
+
+    fn set_passing(&self, passing: bool) {
+        if self.health.passing.swap(passing, Ordering::Relaxed) != passing {
+            if passing {
+                info!("Upstream endpoint {} is healthy again", self.url);
+            } else {
+                warn!("Upstream endpoint {} failed its health checks", self.url);
+            }
+        }
+    }
+}
+
+/// Probes every endpoint of `upstream` forever, taking endpoints out of
+/// rotation after `unhealthy_threshold` failed probes in a row and back
+/// after `healthy_threshold` passing ones.
+pub async fn run_health_checks(upstream: Arc<Upstream>, config: HealthCheckConfig) {
+    let clients = match upstream.endpoints()
+        .iter()
+        .map(|endpoint| upstream.client_builder(endpoint).build())
+        .collect::<Result<Vec<Client>, _>>()
+    {
+        Ok(clients) => clients,
+        Err(e) => {
+            error!("Failed to build health check client for upstream '{}': {}", upstream.name, e);
+            return;
+        }
+    };
+    let mut ticker = interval(Duration::from_secs(config.interval_seconds.max(1)));
+    // Consecutive passing and failing probes per endpoint
+    let mut streaks = vec![(0u32, 0u32); upstream.endpoints().len()];
+
+    loop {
+        ticker.tick().await;
+        let results = join_all(upstream.endpoints().iter().zip(&clients).map(|(e, client)| probe(client, e, &config))).await;
+
+        for ((endpoint, streak), passed) in upstream.endpoints().iter().zip(streaks.iter_mut()).zip(results) {
+            if passed {
+                *streak = (streak.0 + 1, 0);
+                if streak.0 >= config.healthy_threshold {
+                    endpoint.set_passing(true);
+                }
+            } else {
+                *streak = (0, streak.1 + 1);
+                if streak.1 >= config.unhealthy_threshold {
+                    endpoint.set_passing(false);
+                }
+            }
+        }
+    }
+}
+
+async fn probe(client: &Client, endpoint: &Endpoint, config: &HealthCheckConfig) -> bool {
+    let url = health_check_url(&endpoint.request_url, &config.path);
+    match client.get(&url).timeout(Duration::from_secs(config.timeout_seconds)).send().await {
+        Ok(response) => {
+            let passed = response.status().as_u16() == config.expected_status;
+            if !passed {
+                debug!("Health check {} returned {}", url, response.status());
+            }
+            passed
+        }
+        Err(e) => {
+            debug!("Health check {} failed: {}", url, e);
+            false
+        }
+    }
+}
+
+/// WebSocket endpoints are probed over plain HTTP(S).
+fn health_check_url(endpoint_url: &str, path: &str) -> String {
+    let url = if let Some(rest) = endpoint_url.strip_prefix("ws://") {
+        format!("http://{}", rest)
+    } else if let Some(rest) = endpoint_url.strip_prefix("wss://") {
+        format!("https://{}", rest)
+    } else {
+        endpoint_url.to_string()
+    };
+    format!("{}{}", url, path)
+}
@@ -0,0 +1,157 @@
+use futures::StreamExt;
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::time::{interval_at, Instant};
+use tokio_postgres::{Client, Error};
+use crate::db::{self, ApiKey, CertificateBinding, Quota};
+use crate::errors::ProxyError;
+use crate::tls::{normalize_fingerprint, ClientCertificate};
+
+/// API keys, client certificates and product quotas shared by every worker,
+/// swapped atomically on reload.
+pub struct KeyStore {
+    api_keys: RwLock<Arc<HashMap<String, ApiKey>>>,
+    certificates: RwLock<Arc<Certificates>>,
+    quotas: RwLock<Arc<HashMap<i32, Vec<Quota>>>>,
+}
+
+/// Client certificate bindings by fingerprint (lowercase hex, no colons) and by subject.
+#[derive(Default)]
+struct Certificates {
+    by_fingerprint: HashMap<String, CertificateBinding>,
+    by_subject: HashMap<String, CertificateBinding>,
+}
+
+impl Certificates {
+    fn new(bindings: Vec<CertificateBinding>) -> Self {
+        let mut certificates = Certificates::default();
+        for binding in bindings {
+            if let Some(fingerprint) = &binding.fingerprint {
+                certificates.by_fingerprint.insert(normalize_fingerprint(fingerprint), binding.clone());
+            }
+            if let Some(subject) = &binding.subject {
+                certificates.by_subject.insert(subject.clone(), binding.clone());
+            }
+        }
+        certificates
+    }
+}
+
+impl KeyStore {
+    pub fn new(
+        api_keys: HashMap<String, ApiKey>,
+        quotas: HashMap<i32, Vec<Quota>>,
+        certificates: Vec<CertificateBinding>,
+    ) -> Self {
+        KeyStore {
+            api_keys: RwLock::new(Arc::new(api_keys)),
+            certificates: RwLock::new(Arc::new(Certificates::new(certificates))),
+            quotas: RwLock::new(Arc::new(quotas)),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<ApiKey> {
+        self.api_keys.read().unwrap().get(key).cloned()
+    }
+
+    /// The binding for a verified client certificate, by fingerprint first, then by subject.
+    pub fn certificate(&self, certificate: &ClientCertificate) -> Option<CertificateBinding> {
+        let certificates = self.certificates.read().unwrap();
+        certificates
+            .by_fingerprint
+            .get(&certificate.fingerprint)
+            .or_else(|| certificates.by_subject.get(&certificate.subject))
+            .cloned()
+    }
+
+    /// Authenticates a request by its `X-Api-Key`, its client certificate or
+    /// both. When both are present they must belong to the same key, and
+    /// certificates bound with `require_api_key` only count alongside the key.
+    pub fn authenticate(&self, api_key: Option<&str>, certificate: Option<&ClientCertificate>) -> Result<ApiKey, ProxyError> {
+        let api_key = api_key
+            .map(|key| self.get(key).ok_or(ProxyError::InvalidApiKey))
+            .transpose()?;
+        let binding = certificate
+            .map(|certificate| self.certificate(certificate).ok_or(ProxyError::InvalidClientCertificate))
+            .transpose()?;
+
+        match (api_key, binding) {
+            (Some(api_key), Some(binding)) if binding.api_key.id != api_key.id => {
+                Err(ProxyError::InvalidClientCertificate)
+            }
+            (Some(api_key), _) => Ok(api_key),
+            (None, Some(binding)) if !binding.require_api_key => Ok(binding.api_key),
+            (None, _) => Err(ProxyError::MissingApiKey),
+        }
+    }
+
+    pub fn quotas(&self) -> Arc<HashMap<i32, Vec<Quota>>> {
+        Arc::clone(&self.quotas.read().unwrap())
+    }
+
+    pub fn replace(
+        &self,
+        api_keys: HashMap<String, ApiKey>,
+        quotas: HashMap<i32, Vec<Quota>>,
+        certificates: Vec<CertificateBinding>,
+    ) {
+        *self.api_keys.write().unwrap() = Arc::new(api_keys);
+        *self.certificates.write().unwrap() = Arc::new(Certificates::new(certificates));
+        *self.quotas.write().unwrap() = Arc::new(quotas);
+    }
+
+    pub async fn reload(&self, client: &Client) -> Result<(), Error> {
+        let api_keys = db::load_api_keys(client).await?;
+        let quotas = db::load_product_quotas(client).await?;
+        let certificates = db::load_client_certificates(client).await?;
+        debug!(
+            "Reloaded {} API keys, {} client certificates and quotas for {} products",
+            api_keys.len(),
+            certificates.len(),
+            quotas.len()
+        );
+        self.replace(api_keys, quotas, certificates);
+        Ok(())
+    }
+
+    /// Reloads on every `NOTIFY` from Postgres and, as a fallback for missed
+    /// notifications or a lost listener connection, every `reload_interval`.
+    pub async fn watch(self: Arc<Self>, client: Arc<Client>, database_url: String, reload_interval: Duration) {
+        let mut ticker = interval_at(Instant::now() + reload_interval, reload_interval);
+        let mut listener = None;
+
+        loop {
+            if listener.is_none() {
+                match db::listen(&database_url, db::API_KEYS_CHANNEL).await {
+                    Ok(l) => {
+                        info!("Listening for API key changes on '{}'", db::API_KEYS_CHANNEL);
+                        listener = Some(l);
+                    }
+                    Err(e) => error!("Failed to listen for API key changes: {}", e),
+                }
+            }
+
+            match listener.as_mut() {
+                Some((_, notifications)) => tokio::select! {
+                    _ = ticker.tick() => {}
+                    notification = notifications.next() => match notification {
+                        Some(_) => while let Ok(Some(_)) = notifications.try_next() {},
+                        None => {
+                            warn!("API key change listener disconnected");
+                            listener = None;
+                        }
+                    },
+                },
+                None => {
+                    ticker.tick().await;
+                }
+            }
+
+            if let Err(e) = self.reload(&client).await {
+                error!("Failed to reload API keys: {}", e);
+            }
+        }
+    }
+}
@@ -1,14 +1,29 @@
 //! Reverse proxy library
 
+pub mod circuit_breaker;
 pub mod config;
 pub mod db;
+pub mod errors;
 pub mod handlers;
+pub mod health;
+pub mod keys;
 pub mod middleware;
+pub mod rate_limit;
+pub mod retry;
+pub mod routing;
+pub mod tls;
+pub mod upstream;
+pub mod usage;
 
 pub use config::Config;
-pub use db::{connect_to_postgres, init_db, load_api_keys};
+pub use db::{connect_to_postgres, init_db, listen, load_api_keys, load_client_certificates, load_product_quotas, ApiKey, CertificateBinding, Quota};
 pub use handlers::regular::forward_request;
 pub use handlers::ws::ws_handler;
+pub use keys::KeyStore;
 pub use middleware::Middleware;
+pub use rate_limit::{LocalLimiter, RateLimitAlgorithm, RateLimitDecision, RateLimitKey, RateLimitSubject, RateLimiter, RedisFailurePolicy};
+pub use routing::{Route, RouteKind, RouteTable};
+pub use upstream::{LoadBalancing, Upstream};
+pub use usage::{UsageFlusher, UsageRecorder};
 
 pub use log;
\ No newline at end of file
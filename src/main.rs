@@ -1,15 +1,21 @@
 use actix_web::{web, App, HttpServer, HttpRequest};
 use log::{debug, error};
-use num_cpus;
-use reqwest::Client;
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
 
 use reverse_proxy::{
-    handlers, 
+    handlers::{self, grpc},
     config::Config, 
-    db, 
-    middleware::Middleware
+    health,
+    db::{self, ApiKey, Quota}, 
+    errors::ErrorResponses,
+    keys::KeyStore,
+    middleware::Middleware,
+    routing::RouteTable,
+    tls::{self, CertificateResolver},
+    usage::{UsageFlusher, UsageRecorder},
 };
 
 #[actix_web::main]
@@ -18,62 +24,119 @@ async fn main() -> std::io::Result<()> {
 
     let config = Arc::new(Config::from_env().map_err(|e| {
         error!("Failed to load configuration: {}", e);
-        std::io::Error::new(std::io::ErrorKind::Other, e)
+        std::io::Error::other(e)
     })?);
 
-    let pg_client = db::connect_to_postgres(&config.database_url).await.map_err(|e| {
+    let routes = Arc::new(RouteTable::new(&config.proxy, config.forwarded_headers).map_err(|e| {
+        error!("Failed to build routing table: {}", e);
+        std::io::Error::other(e)
+    })?);
+
+    let errors = Arc::new(ErrorResponses::new(&config.proxy.errors).map_err(|e| {
+        error!("Invalid error templates: {}", e);
+        std::io::Error::other(e)
+    })?);
+
+    let pg_client = Arc::new(db::connect_to_postgres(&config.database_url).await.map_err(|e| {
         error!("Failed to connect to database: {}", e);
-        std::io::Error::new(std::io::ErrorKind::Other, e)
-    })?;
+        std::io::Error::other(e)
+    })?);
 
     db::init_db(&pg_client).await.map_err(|e| {
         error!("Failed to initialize database: {}", e);
-        std::io::Error::new(std::io::ErrorKind::Other, e)
+        std::io::Error::other(e)
     })?;
 
-    let api_keys: HashMap<String, bool> = db::load_api_keys(&pg_client).await.map_err(|e| {
+    let api_keys: HashMap<String, ApiKey> = db::load_api_keys(&pg_client).await.map_err(|e| {
         error!("Failed to load API keys: {}", e);
-        std::io::Error::new(std::io::ErrorKind::Other, e)
+        std::io::Error::other(e)
+    })?;
+
+    let quotas: HashMap<i32, Vec<Quota>> = db::load_product_quotas(&pg_client).await.map_err(|e| {
+        error!("Failed to load product quotas: {}", e);
+        std::io::Error::other(e)
     })?;
 
-    let client = Arc::new(Client::new());
-    let config_clone = config.clone();
+    let usage = Arc::new(UsageRecorder::new());
+    let usage_period = Duration::from_secs(config.usage_period_seconds);
+    let usage_flusher = UsageFlusher::new(pg_client.clone(), usage.clone(), usage_period);
+    tokio::spawn(usage_flusher.run(Duration::from_secs(config.usage_flush_interval_seconds)));
+
+    let certificates = db::load_client_certificates(&pg_client).await.map_err(|e| {
+        error!("Failed to load client certificates: {}", e);
+        std::io::Error::other(e)
+    })?;
+
+    let keys = Arc::new(KeyStore::new(api_keys, quotas, certificates));
+    tokio::spawn(keys.clone().watch(
+        pg_client.clone(),
+        config.database_url.clone(),
+        Duration::from_secs(config.api_keys_reload_seconds),
+    ));
+
+    for upstream in routes.upstreams() {
+        if let Some(health_check) = upstream.health_check.clone() {
+            tokio::spawn(health::run_health_checks(upstream.clone(), health_check));
+        }
+    }
 
     let middleware = Middleware::new(
-        api_keys,
-        &config.redis_url,
-        config.http_requests_per_minute,
-        config.ws_connections_per_minute,
+        keys,
+        routes,
+        errors,
+        usage.clone(),
+        &config,
     ).map_err(|e| {
         error!("Failed to create middleware: {}", e);
-        std::io::Error::new(std::io::ErrorKind::Other, "Middleware creation failed")
+        std::io::Error::other("Middleware creation failed")
     })?;
 
-    HttpServer::new(move || {
-        let config = config_clone.clone();
+    let tls = match &config.proxy.tls {
+        Some(tls_config) => {
+            let resolver = Arc::new(CertificateResolver::new(tls_config.certificates.clone()).map_err(|e| {
+                error!("Failed to load TLS certificates: {}", e);
+                std::io::Error::other(e)
+            })?);
+            tokio::spawn(resolver.clone().watch(Duration::from_secs(tls_config.reload_seconds)));
+            let server_config = tls::server_config(tls_config, resolver).map_err(|e| {
+                error!("Invalid TLS configuration: {}", e);
+                std::io::Error::other(e)
+            })?;
+            Some((tls_config.port, server_config))
+        }
+        None => None,
+    };
+
+    if let Some(grpc_config) = &config.proxy.grpc {
+        let acceptor = grpc::tls_acceptor(grpc_config, tls.as_ref().map(|(_, server_config)| server_config))
+            .map_err(|e| {
+                error!("Invalid gRPC configuration: {}", e);
+                std::io::Error::other(e)
+            })?;
+        let listener = TcpListener::bind(("0.0.0.0", grpc_config.port)).await?;
+        tokio::spawn(grpc::serve(listener, acceptor, middleware.clone()));
+    }
+
+    let mut server = HttpServer::new(move || {
         App::new()
             .wrap(middleware.clone()) 
-            .app_data(web::Data::new(client.clone()))
-            .app_data(web::Data::new(config.clone()))
-            .service(
-                web::scope("/ws").route("/{tail:.*}", web::get().to(
-                    |req: HttpRequest, payload: web::Payload, config: web::Data<Arc<Config>>| async move {
-                        debug!("WebSocket route matched for path: {}", req.path());
-                        handlers::ws::ws_handler(req, payload, config).await
-                    }
-                ))
-            )
             .default_service(
                 web::to(
-                    |req: HttpRequest, body: web::Bytes, client: web::Data<Arc<Client>>, config: web::Data<Arc<Config>>| async move {
-                        debug!("Default route matched for path: {}", req.path());
-                        handlers::regular::forward_request(req, body, client, config).await
+                    |req: HttpRequest, payload: web::Payload| async move {
+                        debug!("Proxying request for path: {}", req.path());
+                        handlers::proxy(req, payload).await
                     }
                 ))
     })
+    .on_connect(tls::on_connect)
     .workers(num_cpus::get())
     .max_connections(1000)
-    .bind(("0.0.0.0", config.port))?
-    .run()
-    .await
+    .bind_auto_h2c(("0.0.0.0", config.port))?;
+    if let Some((port, server_config)) = tls {
+        server = server.bind_rustls_0_23(("0.0.0.0", port), server_config)?;
+    }
+    server.run().await?;
+
+    UsageFlusher::new(pg_client, usage, usage_period).flush().await;
+    Ok(())
 }
\ No newline at end of file
@@ -1,88 +1,152 @@
 use actix_web::{
+    body::EitherBody,
     dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
-    Error,
+    Error, HttpMessage,
 };
 use std::future::{ready, Ready};
 use std::pin::Pin;
+use std::rc::Rc;
 use std::sync::Arc;
-use std::collections::HashMap;
-use actix_web::error::{ErrorTooManyRequests, ErrorUnauthorized};
-use redis::{Client, Commands, RedisResult};
-
-pub struct RateLimiter {
-    client: Client,
-    limit: u32,
-    window: i64,
-    prefix: String,
-}
-
-impl RateLimiter {
-    fn new(redis_url: &str, limit: u32, window: u64, prefix: &str) -> RedisResult<Self> {
-        let client = Client::open(redis_url)?;
-        Ok(RateLimiter {
-            client,
-            limit,
-            window: window as i64,
-            prefix: prefix.to_string(),
-        })
-    }
-
-    fn check(&self, ip: &str) -> Result<(), Error> {
-        let mut con = self.client.get_connection()
-            .map_err(|e| Error::from(ErrorTooManyRequests(format!("Redis error: {}", e))))?;
-        let key = format!("{}:rate_limit:{}", self.prefix, ip);
-
-        let current_count: u32 = con.get(&key).unwrap_or(0);
-
-        if current_count >= self.limit {
-            return Err(ErrorTooManyRequests("Rate limit exceeded"));
-        }
-
-        let count: u32 = con.incr(&key, 1)
-            .map_err(|e| Error::from(ErrorTooManyRequests(format!("Redis error: {}", e))))?;
+use std::time::Duration;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use log::error;
+use redis::RedisResult;
+use crate::config::Config;
+use crate::db::{ApiKey, Quota};
+use crate::errors::{self, ErrorResponses, ProxyError, RequestId, REQUEST_ID_HEADER};
+use crate::keys::KeyStore;
+use crate::rate_limit::{LocalLimiter, RateLimitDecision, RateLimitSubject, RateLimiter, RedisConnection};
+use crate::routing::{Route, RouteKind, RouteRequest, RouteTable};
+use crate::tls::ClientCertificate;
+use crate::usage::UsageRecorder;
 
-        if count == 1 {
-            con.expire(&key, self.window)
-                .map_err(|e| Error::from(ErrorTooManyRequests(format!("Redis error: {}", e))))?;
-        }
+const MINUTE: Duration = Duration::from_secs(60);
 
-        Ok(())
-    }
+/// A request that passed `Middleware::admit`.
+pub(crate) struct Admitted {
+    pub api_key: ApiKey,
+    pub route: Arc<Route>,
+    pub decision: RateLimitDecision,
 }
 
 pub struct Middleware {
-    api_keys: Arc<HashMap<String, bool>>,
+    keys: Arc<KeyStore>,
+    routes: Arc<RouteTable>,
+    errors: Arc<ErrorResponses>,
     http_limiter: Arc<RateLimiter>,
     ws_limiter: Arc<RateLimiter>,
+    quota_limiter: Arc<RateLimiter>,
+    usage: Arc<UsageRecorder>,
 }
 
 impl Middleware {
-    pub fn new(api_keys: HashMap<String, bool>, redis_url: &str, http_limit: u32, ws_limit: u32) -> RedisResult<Self> {
+    pub fn new(
+        keys: Arc<KeyStore>,
+        routes: Arc<RouteTable>,
+        errors: Arc<ErrorResponses>,
+        usage: Arc<UsageRecorder>,
+        config: &Config,
+    ) -> RedisResult<Self> {
+        let redis = Arc::new(RedisConnection::new(&config.redis_url, config.redis_failure_policy)?);
+        let local = Arc::new(LocalLimiter::new());
         Ok(Middleware {
-            api_keys: Arc::new(api_keys),
-            http_limiter: Arc::new(RateLimiter::new(redis_url, http_limit, 60, "http")?),
-            ws_limiter: Arc::new(RateLimiter::new(redis_url, ws_limit, 60, "ws")?),
+            keys,
+            routes,
+            errors,
+            http_limiter: Arc::new(RateLimiter::new(
+                redis.clone(),
+                local.clone(),
+                config.http_rate_limit,
+                config.http_requests_per_minute,
+                MINUTE,
+                "http",
+            )),
+            ws_limiter: Arc::new(RateLimiter::new(
+                redis.clone(),
+                local.clone(),
+                config.ws_rate_limit,
+                config.ws_connections_per_minute,
+                MINUTE,
+                "ws",
+            )),
+            quota_limiter: Arc::new(RateLimiter::new(redis, local, config.quota_rate_limit, 0, MINUTE, "quota")),
+            usage,
         })
     }
 
-    fn check_api_key(&self, req: &ServiceRequest) -> Result<(), Error> {
-        match req.headers().get("x-api-key").and_then(|h| h.to_str().ok()) {
-            Some(key) if self.api_keys.contains_key(key) => Ok(()),
-            Some(_) => Err(ErrorUnauthorized("Invalid API Key")),
-            None => Err(ErrorUnauthorized("Missing API Key")),
+    pub(crate) fn routes(&self) -> &RouteTable {
+        &self.routes
+    }
+
+    pub(crate) fn errors(&self) -> &ErrorResponses {
+        &self.errors
+    }
+
+    /// The checks every proxied request goes through: authentication by
+    /// `X-Api-Key`, by the TLS client certificate or both, then the matched
+    /// `route`, then the rate limits. Requests let through count towards usage.
+    pub(crate) async fn admit(
+        &self,
+        api_key: Option<&str>,
+        certificate: Option<&ClientCertificate>,
+        route: Option<Arc<Route>>,
+        ip: &str,
+    ) -> Result<Admitted, (ProxyError, Option<RateLimitDecision>)> {
+        let api_key = self.keys.authenticate(api_key, certificate).map_err(|e| (e, None))?;
+        let route = route.ok_or((ProxyError::NoRoute, None))?;
+        let decision = match self.check_rate_limit(ip, &api_key, &route).await {
+            Ok(decision) => decision,
+            Err(e) => {
+                error!("Rate limit check failed: {}", e);
+                return Err((ProxyError::RateLimiterUnavailable, None));
+            }
+        };
+        if !decision.allowed {
+            return Err((ProxyError::RateLimited, Some(decision)));
         }
+
+        self.usage.record(&api_key);
+        Ok(Admitted { api_key, route, decision })
+    }
+
+    fn find_route(&self, req: &ServiceRequest) -> Option<Arc<Route>> {
+        let connection_info = req.connection_info();
+        let route_request = RouteRequest {
+            method: req.method().as_str(),
+            scheme: connection_info.scheme(),
+            host: Some(connection_info.host()),
+            path: req.path(),
+            headers: req.headers(),
+        };
+        self.routes.find(&route_request)
     }
 
-    fn check_rate_limit(&self, req: &ServiceRequest) -> Result<(), Error> {
-        let ip = req.connection_info().realip_remote_addr()
-            .unwrap_or("unknown")
-            .to_string();
-        let is_websocket = req.headers().contains_key("Sec-WebSocket-Key") || req.path().starts_with("/ws");
+    /// Enforces the quotas of the key's product that apply to this route,
+    /// either by route name or by the kind of traffic, falling back to the
+    /// global limits when there are none. Returns the most restrictive of the
+    /// decisions taken.
+    async fn check_rate_limit(&self, ip: &str, api_key: &ApiKey, route: &Route) -> Result<RateLimitDecision, Error> {
+        let subject = RateLimitSubject {
+            api_key_id: api_key.id,
+            ip,
+            route: &route.name,
+        };
 
-        if is_websocket {
-            self.ws_limiter.check(&ip)
+        let product_quotas = self.keys.quotas();
+        let quotas: Vec<&Quota> = api_key.product_id
+            .and_then(|product_id| product_quotas.get(&product_id))
+            .map(|quotas| quotas.iter().filter(|q| q.feature == route.kind.feature() || q.feature == route.name).collect())
+            .unwrap_or_default();
+
+        if !quotas.is_empty() {
+            let scopes: Vec<_> = quotas.iter().map(|q| (q.feature.as_str(), q.max_requests, q.period)).collect();
+            return self.quota_limiter.check_scoped(&subject, &scopes).await;
+        }
+
+        if route.kind == RouteKind::Websocket {
+            self.ws_limiter.check(&subject).await
         } else {
-            self.http_limiter.check(&ip)
+            self.http_limiter.check(&subject).await
         }
     }
 }
@@ -93,7 +157,7 @@ where
     S::Future: 'static,
     B: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
     type Transform = MiddlewareService<S>;
     type InitError = ();
@@ -101,14 +165,14 @@ where
 
     fn new_transform(&self, service: S) -> Self::Future {
         ready(Ok(MiddlewareService {
-            service,
+            service: Rc::new(service),
             inner: self.clone(),
         }))
     }
 }
 
 pub struct MiddlewareService<S> {
-    service: S,
+    service: Rc<S>,
     inner: Middleware,
 }
 
@@ -118,7 +182,7 @@ where
     S::Future: 'static,
     B: 'static,
 {
-    type Response = ServiceResponse<B>;
+    type Response = ServiceResponse<EitherBody<B>>;
     type Error = Error;
     type Future = Pin<Box<dyn std::future::Future<Output = Result<Self::Response, Self::Error>>>>;
 
@@ -126,30 +190,79 @@ where
 
     fn call(&self, req: ServiceRequest) -> Self::Future {
         let inner = self.inner.clone();
+        let service = Rc::clone(&self.service);
 
-        if let Err(e) = inner.check_api_key(&req) {
-            return Box::pin(async { Err(e) });
-        }
+        Box::pin(async move {
+            let request_id = errors::request_id(
+                req.headers().get(REQUEST_ID_HEADER).and_then(|v| v.to_str().ok()),
+            );
+            req.extensions_mut().insert(RequestId(request_id.clone()));
 
-        if let Err(e) = inner.check_rate_limit(&req) {
-            return Box::pin(async { Err(e) });
-        }
+            let api_key = req.headers().get("x-api-key").and_then(|h| h.to_str().ok());
+            let ip = req.connection_info().realip_remote_addr().unwrap_or("unknown").to_string();
+            let admitted = inner
+                .admit(api_key, req.conn_data::<ClientCertificate>(), inner.find_route(&req), &ip)
+                .await;
+            let Admitted { api_key, route, decision } = match admitted {
+                Ok(admitted) => admitted,
+                Err((e, decision)) => return Ok(inner.reject(req, e, &request_id, decision.as_ref())),
+            };
 
-        let fut = self.service.call(req);
+            req.extensions_mut().insert(api_key);
+            req.extensions_mut().insert(route);
 
-        Box::pin(async move {
-            let res = fut.await?;
+            let res = service.call(req).await?;
+
+            // Errors raised by the handlers get the configured error body
+            let proxy_error = res.response().error().and_then(|e| e.as_error::<ProxyError>()).copied();
+            let mut res = match proxy_error {
+                Some(e) => {
+                    let (req, _) = res.into_parts();
+                    ServiceResponse::new(req, inner.errors.response(e, &request_id)).map_into_right_body()
+                }
+                None => res.map_into_left_body(),
+            };
+            decision.apply_headers(res.headers_mut());
+            insert_request_id(res.headers_mut(), &request_id);
             Ok(res)
         })
     }
 }
 
+impl Middleware {
+    /// Answers the request with an error, without calling the service.
+    fn reject<B>(
+        &self,
+        req: ServiceRequest,
+        error: ProxyError,
+        request_id: &str,
+        decision: Option<&RateLimitDecision>,
+    ) -> ServiceResponse<EitherBody<B>> {
+        let mut response = self.errors.response(error, request_id);
+        if let Some(decision) = decision {
+            decision.apply_headers(response.headers_mut());
+        }
+        insert_request_id(response.headers_mut(), request_id);
+        req.into_response(response).map_into_right_body()
+    }
+}
+
+fn insert_request_id(headers: &mut actix_web::http::header::HeaderMap, request_id: &str) {
+    if let Ok(value) = HeaderValue::from_str(request_id) {
+        headers.insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+    }
+}
+
 impl Clone for Middleware {
     fn clone(&self) -> Self {
         Middleware {
-            api_keys: Arc::clone(&self.api_keys),
+            keys: Arc::clone(&self.keys),
+            routes: Arc::clone(&self.routes),
+            errors: Arc::clone(&self.errors),
             http_limiter: Arc::clone(&self.http_limiter),
             ws_limiter: Arc::clone(&self.ws_limiter),
+            quota_limiter: Arc::clone(&self.quota_limiter),
+            usage: Arc::clone(&self.usage),
         }
     }
-}
\ No newline at end of file
+}
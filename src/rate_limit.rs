@@ -0,0 +1,615 @@
+use actix_web::error::ErrorServiceUnavailable;
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::Error;
+use redis::aio::{ConnectionManager, ConnectionManagerConfig};
+use log::{info, warn};
+use redis::{Client, RedisError, RedisResult, Script};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::OnceCell;
+
+const REDIS_TIMEOUT: Duration = Duration::from_millis(500);
+const REDIS_RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+// Every algorithm is a Lua function `check(key, limit, window_ms, burst, commit)`
+// returning `{allowed, remaining, reset_ms}`, which only counts the hit when
+// `commit` is true. `CHECK_ALL_SCRIPT` runs it for every key in `KEYS`, with
+// `limit`, `window_ms` and `burst` for each in `ARGV`. Time comes from Redis
+// so that all proxy instances share one clock.
+
+/// Counter reset at the end of each window.
+const FIXED_WINDOW_SCRIPT: &str = r"
+local function check(key, limit, window, burst, commit)
+    local current = tonumber(redis.call('GET', key) or '0')
+    local allowed = 0
+    if current < limit then
+        if commit then
+            current = redis.call('INCR', key)
+        else
+            current = current + 1
+        end
+        allowed = 1
+    end
+    local ttl = redis.call('PTTL', key)
+    if ttl < 0 then
+        if commit then
+            redis.call('PEXPIRE', key, window)
+        end
+        ttl = window
+    end
+    return {allowed, math.max(limit - current, 0), ttl}
+end
+";
+
+/// Sorted set holding the timestamp of every accepted request in the last window.
+const SLIDING_LOG_SCRIPT: &str = r"
+local function check(key, limit, window, burst, commit)
+    local time = redis.call('TIME')
+    local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    redis.call('ZREMRANGEBYSCORE', key, '-inf', now - window)
+    local count = redis.call('ZCARD', key)
+    local allowed = 0
+    if count < limit then
+        if commit then
+            redis.call('ZADD', key, now, time[1] .. '.' .. time[2] .. '.' .. count)
+        end
+        count = count + 1
+        allowed = 1
+    end
+    if commit then
+        redis.call('PEXPIRE', key, window)
+    end
+    local reset = window
+    local oldest = redis.call('ZRANGE', key, 0, 0, 'WITHSCORES')
+    if oldest[2] then
+        reset = tonumber(oldest[2]) + window - now
+    end
+    return {allowed, math.max(limit - count, 0), reset}
+end
+";
+
+/// Current window counter plus the previous one weighted by how much of it
+/// still overlaps the sliding window.
+const SLIDING_WINDOW_SCRIPT: &str = r"
+local function check(key, limit, window, burst, commit)
+    local time = redis.call('TIME')
+    local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    local index = math.floor(now / window)
+    local current_key = key .. ':' .. index
+    local current = tonumber(redis.call('GET', current_key) or '0')
+    local previous = tonumber(redis.call('GET', key .. ':' .. (index - 1)) or '0')
+    local elapsed = now - index * window
+    local weighted = previous * (window - elapsed) / window + current
+    local allowed = 0
+    if weighted + 1 <= limit then
+        if commit then
+            redis.call('INCR', current_key)
+            redis.call('PEXPIRE', current_key, window * 2)
+        end
+        weighted = weighted + 1
+        allowed = 1
+    end
+    return {allowed, math.max(math.floor(limit - weighted), 0), window - elapsed}
+end
+";
+
+/// Bucket of `burst` tokens refilled at `limit` tokens per window.
+const TOKEN_BUCKET_SCRIPT: &str = r"
+local function check(key, limit, window, burst, commit)
+    local rate = limit / window
+    local capacity = burst
+    local time = redis.call('TIME')
+    local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+    local bucket = redis.call('HMGET', key, 'tokens', 'ts')
+    local tokens = tonumber(bucket[1]) or capacity
+    local ts = tonumber(bucket[2]) or now
+    tokens = math.min(capacity, tokens + math.max(now - ts, 0) * rate)
+    local allowed = 0
+    if tokens >= 1 then
+        tokens = tokens - 1
+        allowed = 1
+    end
+    if commit then
+        redis.call('HSET', key, 'tokens', tostring(tokens), 'ts', now)
+        redis.call('PEXPIRE', key, math.ceil(capacity / rate))
+    end
+    local reset
+    if allowed == 1 then
+        reset = math.ceil((capacity - tokens) / rate)
+    else
+        reset = math.ceil((1 - tokens) / rate)
+    end
+    return {allowed, math.floor(tokens), reset}
+end
+";
+
+/// Checks every key without counting, then counts the hit against all of
+/// them only if each one allows it, so a rejected request uses up nothing.
+/// Returns the three values of each key's result in order.
+const CHECK_ALL_SCRIPT: &str = r"
+local results = {}
+local function run(commit)
+    local all_allowed = true
+    for i, key in ipairs(KEYS) do
+        local base = (i - 1) * 3
+        results[i] = check(key, tonumber(ARGV[base + 1]), tonumber(ARGV[base + 2]), tonumber(ARGV[base + 3]), commit)
+        if results[i][1] == 0 then
+            all_allowed = false
+        end
+    end
+    return all_allowed
+end
+if #KEYS == 1 or run(false) then
+    run(true)
+end
+local reply = {}
+for _, result in ipairs(results) do
+    for _, value in ipairs(result) do
+        table.insert(reply, value)
+    end
+end
+return reply
+";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitAlgorithm {
+    FixedWindow,
+    SlidingLog,
+    SlidingWindow,
+    TokenBucket,
+}
+
+impl RateLimitAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            RateLimitAlgorithm::FixedWindow => "fixed_window",
+            RateLimitAlgorithm::SlidingLog => "sliding_log",
+            RateLimitAlgorithm::SlidingWindow => "sliding_window",
+            RateLimitAlgorithm::TokenBucket => "token_bucket",
+        }
+    }
+
+    fn script(self) -> &'static str {
+        match self {
+            RateLimitAlgorithm::FixedWindow => FIXED_WINDOW_SCRIPT,
+            RateLimitAlgorithm::SlidingLog => SLIDING_LOG_SCRIPT,
+            RateLimitAlgorithm::SlidingWindow => SLIDING_WINDOW_SCRIPT,
+            RateLimitAlgorithm::TokenBucket => TOKEN_BUCKET_SCRIPT,
+        }
+    }
+}
+
+impl FromStr for RateLimitAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed_window" => Ok(RateLimitAlgorithm::FixedWindow),
+            "sliding_log" => Ok(RateLimitAlgorithm::SlidingLog),
+            "sliding_window" => Ok(RateLimitAlgorithm::SlidingWindow),
+            "token_bucket" => Ok(RateLimitAlgorithm::TokenBucket),
+            _ => Err(format!("unknown rate limit algorithm '{}'", s)),
+        }
+    }
+}
+
+/// What requests are grouped by when counting them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RateLimitKey {
+    Ip,
+    ApiKey,
+    ApiKeyIp,
+    ApiKeyRoute,
+}
+
+impl RateLimitKey {
+    /// Identifier of the counter `subject` is counted against, prefixed with the
+    /// key kind so that counters of different kinds never collide in Redis.
+    pub fn id(self, subject: &RateLimitSubject) -> String {
+        match self {
+            RateLimitKey::Ip => format!("ip:{}", subject.ip),
+            RateLimitKey::ApiKey => format!("key:{}", subject.api_key_id),
+            RateLimitKey::ApiKeyIp => format!("key_ip:{}:{}", subject.api_key_id, subject.ip),
+            RateLimitKey::ApiKeyRoute => format!("key_route:{}:{}", subject.api_key_id, subject.route),
+        }
+    }
+}
+
+impl FromStr for RateLimitKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ip" => Ok(RateLimitKey::Ip),
+            "api_key" => Ok(RateLimitKey::ApiKey),
+            "api_key_ip" => Ok(RateLimitKey::ApiKeyIp),
+            "api_key_route" => Ok(RateLimitKey::ApiKeyRoute),
+            _ => Err(format!("unknown rate limit key '{}'", s)),
+        }
+    }
+}
+
+/// The caller of a request, as far as rate limiting is concerned.
+pub struct RateLimitSubject<'a> {
+    pub api_key_id: i32,
+    pub ip: &'a str,
+    pub route: &'a str,
+}
+
+/// Algorithm, burst size and key of one limiter, as read from the environment.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimitSettings {
+    pub algorithm: RateLimitAlgorithm,
+    pub key: RateLimitKey,
+    /// Bucket capacity for `TokenBucket`; defaults to the limit.
+    pub burst: Option<u32>,
+}
+
+/// Outcome of a single rate limit check.
+#[derive(Clone, Debug)]
+pub struct RateLimitDecision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    pub reset_after: Duration,
+}
+
+impl RateLimitDecision {
+    /// Seconds until the limit resets, rounded up so clients never retry too early.
+    pub fn reset_seconds(&self) -> u64 {
+        self.reset_after.as_millis().div_ceil(1000) as u64
+    }
+
+    /// `RateLimit-Limit`, `RateLimit-Remaining` and `RateLimit-Reset`, plus
+    /// `Retry-After` when the request was rejected.
+    pub fn headers(&self) -> Vec<(&'static str, u64)> {
+        let mut headers = vec![
+            ("ratelimit-limit", u64::from(self.limit)),
+            ("ratelimit-remaining", u64::from(self.remaining)),
+            ("ratelimit-reset", self.reset_seconds()),
+        ];
+        if !self.allowed {
+            headers.push(("retry-after", self.reset_seconds().max(1)));
+        }
+        headers
+    }
+
+    /// Sets the headers from `headers()` on a response.
+    pub fn apply_headers(&self, headers: &mut HeaderMap) {
+        for (name, value) in self.headers() {
+            headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+        }
+    }
+
+    /// Of two decisions for the same request, keeps the one the client has to honour:
+    /// a rejection first, otherwise the one with the fewest requests left.
+    pub fn most_restrictive(self, other: RateLimitDecision) -> RateLimitDecision {
+        match (self.allowed, other.allowed) {
+            (true, false) => other,
+            (false, true) => self,
+            _ if other.remaining < self.remaining => other,
+            _ => self,
+        }
+    }
+}
+
+/// What limiters do while Redis cannot be reached.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RedisFailurePolicy {
+    /// Let every request through.
+    FailOpen,
+    /// Reject every request with `503 Service Unavailable`.
+    FailClosed,
+    /// Count requests in memory with `LocalLimiter`.
+    LocalFallback,
+}
+
+impl RedisFailurePolicy {
+    fn name(self) -> &'static str {
+        match self {
+            RedisFailurePolicy::FailOpen => "fail_open",
+            RedisFailurePolicy::FailClosed => "fail_closed",
+            RedisFailurePolicy::LocalFallback => "local",
+        }
+    }
+}
+
+impl FromStr for RedisFailurePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fail_open" => Ok(RedisFailurePolicy::FailOpen),
+            "fail_closed" => Ok(RedisFailurePolicy::FailClosed),
+            "local" => Ok(RedisFailurePolicy::LocalFallback),
+            _ => Err(format!("unknown Redis failure policy '{}'", s)),
+        }
+    }
+}
+
+/// Fixed-window counters kept in process memory, used while Redis is down.
+/// Counters are shared by the workers of one proxy instance but not across
+/// instances, so limits are only approximate behind a load balancer.
+#[derive(Default)]
+pub struct LocalLimiter {
+    windows: Mutex<HashMap<String, LocalWindow>>,
+}
+
+/// A key's current window, with the length of the limit it counts for.
+struct LocalWindow {
+    start: Instant,
+    length: Duration,
+    count: u32,
+}
+
+impl LocalLimiter {
+    const MAX_TRACKED_KEYS: usize = 100_000;
+    /// Keys kept when the map is full of live windows, so the next sweep is
+    /// only needed after this many new keys.
+    const KEYS_AFTER_EVICTION: usize = Self::MAX_TRACKED_KEYS * 9 / 10;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn check(&self, key: &str, limit: u32, window: Duration) -> RateLimitDecision {
+        self.check_all(&[(key, limit, window)]).remove(0)
+    }
+
+    /// Counts one hit against every `(key, limit, window)` if all of them
+    /// have room; otherwise nothing is counted. Returns a decision per key.
+    pub fn check_all(&self, checks: &[(&str, u32, Duration)]) -> Vec<RateLimitDecision> {
+        let now = Instant::now();
+        let mut windows = self.windows.lock().unwrap();
+
+        for &(key, _, window) in checks {
+            if windows.len() >= Self::MAX_TRACKED_KEYS && !windows.contains_key(key) {
+                Self::evict(&mut windows, now);
+            }
+            let entry = windows
+                .entry(key.to_string())
+                .or_insert(LocalWindow { start: now, length: window, count: 0 });
+            if now.duration_since(entry.start) >= window {
+                entry.start = now;
+                entry.count = 0;
+            }
+            entry.length = window;
+        }
+
+        let all_allowed = checks.iter().all(|&(key, limit, _)| windows[key].count < limit);
+        checks
+            .iter()
+            .map(|&(key, limit, window)| {
+                let entry = windows.get_mut(key).expect("window was just inserted");
+                let allowed = entry.count < limit;
+                if all_allowed {
+                    entry.count += 1;
+                }
+                RateLimitDecision {
+                    allowed,
+                    limit,
+                    remaining: limit.saturating_sub(entry.count),
+                    reset_after: window.saturating_sub(now.duration_since(entry.start)),
+                }
+            })
+            .collect()
+    }
+
+    /// Drops windows that have ended, each by its own length, then the
+    /// oldest live ones if that did not free enough room.
+    fn evict(windows: &mut HashMap<String, LocalWindow>, now: Instant) {
+        windows.retain(|_, w| now.duration_since(w.start) < w.length);
+        if windows.len() <= Self::KEYS_AFTER_EVICTION {
+            return;
+        }
+        let mut starts: Vec<Instant> = windows.values().map(|w| w.start).collect();
+        let excess = windows.len() - Self::KEYS_AFTER_EVICTION;
+        let (_, cutoff, _) = starts.select_nth_unstable(excess - 1);
+        let cutoff = *cutoff;
+        let mut evicted = 0;
+        windows.retain(|_, w| {
+            if evicted < excess && w.start <= cutoff {
+                evicted += 1;
+                false
+            } else {
+                true
+            }
+        });
+        warn!("Local rate limiter full, evicted the {} oldest live windows", evicted);
+    }
+}
+
+/// Multiplexed Redis connection shared by all limiters, established on first use
+/// so that the proxy can start while Redis is still coming up. After a failure,
+/// Redis is not tried again for `REDIS_RETRY_INTERVAL` so that requests do not
+/// each wait for a timeout during an outage.
+pub struct RedisConnection {
+    client: Client,
+    manager: OnceCell<ConnectionManager>,
+    failure_policy: RedisFailurePolicy,
+    down_since: Mutex<Option<Instant>>,
+}
+
+impl RedisConnection {
+    pub fn new(redis_url: &str, failure_policy: RedisFailurePolicy) -> RedisResult<Self> {
+        Ok(RedisConnection {
+            client: Client::open(redis_url)?,
+            manager: OnceCell::new(),
+            failure_policy,
+            down_since: Mutex::new(None),
+        })
+    }
+
+    async fn get(&self) -> RedisResult<ConnectionManager> {
+        self.manager
+            .get_or_try_init(|| {
+                let config = ConnectionManagerConfig::new()
+                    .set_number_of_retries(1)
+                    .set_connection_timeout(REDIS_TIMEOUT)
+                    .set_response_timeout(REDIS_TIMEOUT);
+                ConnectionManager::new_with_config(self.client.clone(), config)
+            })
+            .await
+            .cloned()
+    }
+
+    /// Whether Redis failed less than `REDIS_RETRY_INTERVAL` ago.
+    fn is_down(&self) -> bool {
+        matches!(*self.down_since.lock().unwrap(), Some(since) if since.elapsed() < REDIS_RETRY_INTERVAL)
+    }
+
+    fn mark_down(&self, error: &RedisError) {
+        let mut down_since = self.down_since.lock().unwrap();
+        if down_since.is_none() {
+            warn!(
+                "Redis unavailable ({}), rate limiting switches to '{}' mode",
+                error,
+                self.failure_policy.name()
+            );
+        }
+        *down_since = Some(Instant::now());
+    }
+
+    fn mark_up(&self) {
+        let mut down_since = self.down_since.lock().unwrap();
+        if let Some(since) = down_since.take() {
+            info!("Redis available again after {:?}, rate limiting back on Redis", since.elapsed());
+        }
+    }
+}
+
+pub struct RateLimiter {
+    redis: Arc<RedisConnection>,
+    local: Arc<LocalLimiter>,
+    script: Script,
+    settings: RateLimitSettings,
+    limit: u32,
+    window: Duration,
+    prefix: String,
+}
+
+impl RateLimiter {
+    pub fn new(
+        redis: Arc<RedisConnection>,
+        local: Arc<LocalLimiter>,
+        settings: RateLimitSettings,
+        limit: u32,
+        window: Duration,
+        prefix: &str,
+    ) -> Self {
+        RateLimiter {
+            redis,
+            local,
+            script: Script::new(&format!("{}{}", settings.algorithm.script(), CHECK_ALL_SCRIPT)),
+            settings,
+            limit,
+            window,
+            prefix: prefix.to_string(),
+        }
+    }
+
+    /// Checks `subject` against the limiter's own limit, keyed as configured.
+    pub async fn check(&self, subject: &RateLimitSubject<'_>) -> Result<RateLimitDecision, Error> {
+        self.check_all(&[(self.settings.key.id(subject), self.limit, self.window)]).await
+    }
+
+    /// Checks `subject` against several `(scope, limit, window)` limits, each
+    /// on a counter of its own per scope, e.g. a product feature. The request
+    /// is counted against all of them or, if any rejects it, against none.
+    /// Returns the most restrictive decision.
+    pub async fn check_scoped(
+        &self,
+        subject: &RateLimitSubject<'_>,
+        scopes: &[(&str, u32, Duration)],
+    ) -> Result<RateLimitDecision, Error> {
+        let id = self.settings.key.id(subject);
+        let checks: Vec<_> = scopes
+            .iter()
+            .map(|&(scope, limit, window)| (format!("{}:{}", scope, id), limit, window))
+            .collect();
+        self.check_all(&checks).await
+    }
+
+    /// Counts one hit against every `(id, limit, window)` if all of them have
+    /// room, applying the Redis failure policy when Redis cannot answer.
+    async fn check_all(&self, checks: &[(String, u32, Duration)]) -> Result<RateLimitDecision, Error> {
+        let burst = |limit: u32| match self.settings.algorithm {
+            RateLimitAlgorithm::TokenBucket => self.settings.burst.unwrap_or(limit),
+            _ => limit,
+        };
+        if let Some(&(_, _, window)) = checks.iter().find(|&&(_, limit, _)| limit == 0 || burst(limit) == 0) {
+            return Ok(RateLimitDecision {
+                allowed: false,
+                limit: 0,
+                remaining: 0,
+                reset_after: window,
+            });
+        }
+
+        let keyed: Vec<_> = checks
+            .iter()
+            .map(|(id, limit, window)| {
+                (format!("{}:rate_limit:{}:{}", self.prefix, self.settings.algorithm.name(), id), *limit, *window)
+            })
+            .collect();
+        if !self.redis.is_down() {
+            let redis_checks: Vec<_> = keyed.iter().map(|(key, limit, window)| (key.as_str(), *limit, *window, burst(*limit))).collect();
+            match self.check_redis(&redis_checks).await {
+                Ok(decision) => {
+                    self.redis.mark_up();
+                    return Ok(decision);
+                }
+                Err(e) => self.redis.mark_down(&e),
+            }
+        }
+
+        match self.redis.failure_policy {
+            RedisFailurePolicy::FailOpen => Ok(keyed
+                .iter()
+                .map(|&(_, limit, window)| RateLimitDecision {
+                    allowed: true,
+                    limit,
+                    remaining: limit,
+                    reset_after: window,
+                })
+                .reduce(RateLimitDecision::most_restrictive)
+                .expect("at least one limit was checked")),
+            RedisFailurePolicy::FailClosed => Err(ErrorServiceUnavailable("Rate limiter unavailable")),
+            RedisFailurePolicy::LocalFallback => {
+                let local_checks: Vec<_> = keyed.iter().map(|(key, limit, window)| (key.as_str(), *limit, *window)).collect();
+                Ok(self.local
+                    .check_all(&local_checks)
+                    .into_iter()
+                    .reduce(RateLimitDecision::most_restrictive)
+                    .expect("at least one limit was checked"))
+            }
+        }
+    }
+
+    /// Runs the algorithm's script over every `(key, limit, window, burst)`.
+    /// The checks and the updates happen atomically, so concurrent requests
+    /// cannot both observe the last free slot.
+    async fn check_redis(&self, checks: &[(&str, u32, Duration, u32)]) -> RedisResult<RateLimitDecision> {
+        let mut con = self.redis.get().await?;
+
+        let mut invocation = self.script.prepare_invoke();
+        for &(key, limit, window, burst) in checks {
+            invocation.key(key).arg(limit).arg(window.as_millis().max(1) as u64).arg(burst);
+        }
+        let reply: Vec<i64> = invocation.invoke_async(&mut con).await?;
+
+        Ok(reply
+            .chunks_exact(3)
+            .zip(checks)
+            .map(|(result, &(_, _, _, burst))| RateLimitDecision {
+                allowed: result[0] == 1,
+                limit: burst,
+                remaining: u32::try_from(result[1].max(0)).unwrap_or(u32::MAX),
+                reset_after: Duration::from_millis(result[2].max(0) as u64),
+            })
+            .reduce(RateLimitDecision::most_restrictive)
+            .expect("at least one limit was checked"))
+    }
+}
@@ -0,0 +1,79 @@
+use rand::Rng;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::config::RetryConfig;
+
+/// Window over which the retry budget is counted.
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// Methods that can be sent twice without changing the outcome (RFC 7231, section 4.2.2).
+const IDEMPOTENT_METHODS: [&str; 6] = ["GET", "HEAD", "OPTIONS", "TRACE", "PUT", "DELETE"];
+
+pub fn is_idempotent(method: &str) -> bool {
+    IDEMPOTENT_METHODS.contains(&method)
+}
+
+/// How a route retries failed upstream requests.
+#[derive(Debug)]
+pub struct RetryPolicy {
+    pub config: RetryConfig,
+    budget: Mutex<Budget>,
+}
+
+#[derive(Debug)]
+struct Budget {
+    window_start: Instant,
+    requests: u32,
+    retries: u32,
+}
+
+impl RetryPolicy {
+    pub fn new(config: RetryConfig) -> Self {
+        RetryPolicy {
+            config,
+            budget: Mutex::new(Budget { window_start: Instant::now(), requests: 0, retries: 0 }),
+        }
+    }
+
+    pub fn retries_status(&self, status: u16) -> bool {
+        self.config.retry_on.contains(&status)
+    }
+
+    /// Counts a request towards the budget. Called once per client request,
+    /// not per attempt.
+    pub fn record_request(&self) {
+        self.current_budget(|budget| budget.requests += 1);
+    }
+
+    /// Takes a retry from the budget, or returns `false` when retries already
+    /// make up too large a share of recent traffic.
+    pub fn try_retry(&self) -> bool {
+        let (ratio, floor) = (self.config.budget_ratio, self.config.min_retries_per_second);
+        self.current_budget(|budget| {
+            let allowed = ratio * f64::from(budget.requests) + f64::from(floor) * BUDGET_WINDOW.as_secs_f64();
+            if f64::from(budget.retries) < allowed {
+                budget.retries += 1;
+                true
+            } else {
+                false
+            }
+        })
+    }
+
+    /// Wait before retry number `retry` (starting at 1): a random duration up
+    /// to `backoff_ms * 2^(retry - 1)`, capped at `max_backoff_ms`.
+    pub fn backoff(&self, retry: u32) -> Duration {
+        let step = self.config.backoff_ms
+            .saturating_mul(1u64 << retry.saturating_sub(1).min(32))
+            .min(self.config.max_backoff_ms);
+        Duration::from_millis(rand::thread_rng().gen_range(0..=step))
+    }
+
+    fn current_budget<T>(&self, f: impl FnOnce(&mut Budget) -> T) -> T {
+        let mut budget = self.budget.lock().unwrap();
+        if budget.window_start.elapsed() >= BUDGET_WINDOW {
+            *budget = Budget { window_start: Instant::now(), requests: 0, retries: 0 };
+        }
+        f(&mut budget)
+    }
+}
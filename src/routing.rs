@@ -0,0 +1,296 @@
+use actix_web::http::header::HeaderMap;
+use actix_web::http::Method;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::config::{ConfigError, ProxyConfig, RouteConfig, SseConfig, TimeoutConfig, WebSocketConfig};
+use crate::handlers::headers::ForwardedHeaders;
+use crate::handlers::sse::StreamLimiter;
+use crate::retry::RetryPolicy;
+use crate::upstream::{build_upstreams, Endpoint, HttpVersion, Upstream};
+
+/// How requests on a route are proxied.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteKind {
+    #[default]
+    Http,
+    Websocket,
+    /// Served on the gRPC listener only, over HTTP/2 to the upstream.
+    Grpc,
+}
+
+impl RouteKind {
+    /// Feature name whose quotas apply to every route of this kind.
+    pub fn feature(self) -> &'static str {
+        match self {
+            RouteKind::Http => "http",
+            RouteKind::Websocket => "ws",
+            RouteKind::Grpc => "grpc",
+        }
+    }
+}
+
+/// Header access shared by the different `HeaderMap` types routes are matched against.
+pub trait HeaderLookup {
+    fn get_str(&self, name: &str) -> Option<&str>;
+}
+
+impl HeaderLookup for HeaderMap {
+    fn get_str(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|v| v.to_str().ok())
+    }
+}
+
+impl HeaderLookup for http::HeaderMap {
+    fn get_str(&self, name: &str) -> Option<&str> {
+        self.get(name).and_then(|v| v.to_str().ok())
+    }
+}
+
+/// The parts of a request routes are matched against.
+pub struct RouteRequest<'a, H: HeaderLookup> {
+    pub method: &'a str,
+    pub scheme: &'a str,
+    pub host: Option<&'a str>,
+    pub path: &'a str,
+    pub headers: &'a H,
+}
+
+/// A compiled `[[routes]]` entry. All configured matchers must match.
+#[derive(Debug)]
+pub struct Route {
+    pub name: String,
+    pub kind: RouteKind,
+    pub upstream: Arc<Upstream>,
+    pub forwarded_headers: ForwardedHeaders,
+    pub timeouts: TimeoutConfig,
+    pub retry: Option<RetryPolicy>,
+    pub sse: SseConfig,
+    pub websocket: WebSocketConfig,
+    /// Open event streams per API key, bounded by `sse.max_streams_per_key`.
+    streams: Arc<StreamLimiter>,
+    /// One client per upstream endpoint, with the route's connect timeout.
+    clients: Vec<reqwest::Client>,
+    path_prefix: Option<String>,
+    path_regex: Option<Regex>,
+    host: Option<String>,
+    methods: Vec<Method>,
+    headers: Vec<(String, String)>,
+    scheme: Option<String>,
+    strip_prefix: Option<String>,
+    add_prefix: Option<String>,
+    rewrite: Option<String>,
+}
+
+impl Route {
+    fn new(
+        config: &RouteConfig,
+        upstreams: &HashMap<String, Arc<Upstream>>,
+        default_forwarded_headers: ForwardedHeaders,
+    ) -> Result<Self, ConfigError> {
+        let invalid = |message: String| ConfigError::Invalid(format!("route '{}': {}", config.name, message));
+
+        let upstream = upstreams
+            .get(&config.upstream)
+            .cloned()
+            .ok_or_else(|| invalid(format!("unknown upstream '{}'", config.upstream)))?;
+        let path_regex = config.path_regex
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .map_err(|e| invalid(format!("invalid path_regex: {}", e)))?;
+        if config.rewrite.is_some() && path_regex.is_none() {
+            return Err(invalid("rewrite requires path_regex".to_string()));
+        }
+        let methods = config.methods
+            .iter()
+            .map(|m| Method::from_str(&m.to_ascii_uppercase()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid(format!("invalid method: {}", e)))?;
+        let grpc = config.kind == RouteKind::Grpc;
+        if grpc && upstream.http_version == HttpVersion::Http1 {
+            return Err(invalid("grpc routes need an upstream that speaks HTTP/2".to_string()));
+        }
+        if config.websocket.client_buffer == 0 || config.websocket.upstream_buffer == 0 {
+            return Err(invalid("websocket buffers must hold at least one message".to_string()));
+        }
+        if ![1008, 1013].contains(&config.websocket.close_code) {
+            return Err(invalid(format!("websocket close_code must be 1008 or 1013, not {}", config.websocket.close_code)));
+        }
+        let clients = upstream.endpoints()
+            .iter()
+            .map(|endpoint| {
+                let builder = upstream.client_builder(endpoint)
+                    .connect_timeout(Duration::from_millis(config.timeouts.connect_ms));
+                if grpc { builder.http2_prior_knowledge() } else { builder }.build()
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| invalid(format!("failed to build HTTP client: {}", e)))?;
+
+        Ok(Route {
+            name: config.name.clone(),
+            kind: config.kind,
+            upstream,
+            forwarded_headers: config.forwarded_headers.unwrap_or(default_forwarded_headers),
+            timeouts: config.timeouts,
+            retry: config.retries.clone().map(RetryPolicy::new),
+            sse: config.sse,
+            websocket: config.websocket,
+            streams: Arc::new(StreamLimiter::new(config.sse.max_streams_per_key)),
+            clients,
+            path_prefix: config.path_prefix.clone(),
+            path_regex,
+            host: config.host.as_ref().map(|h| h.to_ascii_lowercase()),
+            methods,
+            headers: config.headers
+                .iter()
+                .map(|(name, value)| (name.to_ascii_lowercase(), value.clone()))
+                .collect(),
+            scheme: config.scheme.clone(),
+            strip_prefix: config.strip_prefix.clone(),
+            add_prefix: config.add_prefix.clone(),
+            rewrite: config.rewrite.clone(),
+        })
+    }
+
+    pub fn matches<H: HeaderLookup>(&self, req: &RouteRequest<H>) -> bool {
+        if let Some(prefix) = &self.path_prefix {
+            if !path_has_prefix(req.path, prefix) {
+                return false;
+            }
+        }
+        if let Some(regex) = &self.path_regex {
+            if !regex.is_match(req.path) {
+                return false;
+            }
+        }
+        if let Some(host) = &self.host {
+            match req.host.map(strip_port) {
+                Some(req_host) if host_matches(host, &req_host.to_ascii_lowercase()) => {}
+                _ => return false,
+            }
+        }
+        if !self.methods.is_empty() && !self.methods.iter().any(|m| m.as_str() == req.method) {
+            return false;
+        }
+        if let Some(scheme) = &self.scheme {
+            if !scheme.eq_ignore_ascii_case(req.scheme) {
+                return false;
+            }
+        }
+        self.headers
+            .iter()
+            .all(|(name, value)| req.headers.get_str(name) == Some(value.as_str()))
+    }
+
+    /// The path to request from the upstream: rewritten with `path_regex` and
+    /// `rewrite`, then with `strip_prefix` removed and `add_prefix` prepended.
+    pub fn upstream_path(&self, path: &str) -> String {
+        let mut path = path.to_string();
+        if let (Some(regex), Some(rewrite)) = (&self.path_regex, &self.rewrite) {
+            path = regex.replace(&path, rewrite.as_str()).into_owned();
+        }
+        if let Some(prefix) = &self.strip_prefix {
+            if let Some(rest) = path.strip_prefix(prefix.as_str()) {
+                path = rest.to_string();
+            }
+        }
+        if let Some(prefix) = &self.add_prefix {
+            path = format!("{}{}", prefix, path);
+        }
+        path
+    }
+
+    pub fn first_byte_timeout(&self) -> Duration {
+        Duration::from_millis(self.timeouts.first_byte_ms)
+    }
+
+    pub fn total_timeout(&self) -> Option<Duration> {
+        self.timeouts.total_ms.map(Duration::from_millis)
+    }
+
+    pub fn sse_idle_timeout(&self) -> Duration {
+        Duration::from_millis(self.sse.idle_timeout_ms)
+    }
+
+    pub fn streams(&self) -> &Arc<StreamLimiter> {
+        &self.streams
+    }
+
+    /// The client for requests to `endpoint`.
+    pub fn client(&self, endpoint: &Endpoint) -> &reqwest::Client {
+        &self.clients[endpoint.index]
+    }
+
+    /// Full URL on `endpoint` for a request path and optional query string.
+    pub fn upstream_url(&self, endpoint: &Endpoint, path: &str, query: Option<&str>) -> String {
+        let mut url = format!("{}{}", endpoint.request_url, self.upstream_path(path));
+        if let Some(query) = query.filter(|q| !q.is_empty()) {
+            url.push('?');
+            url.push_str(query);
+        }
+        url
+    }
+}
+
+/// Routes in declaration order; the first matching route wins.
+#[derive(Debug)]
+pub struct RouteTable {
+    routes: Vec<Arc<Route>>,
+    upstreams: Vec<Arc<Upstream>>,
+}
+
+impl RouteTable {
+    pub fn new(config: &ProxyConfig, default_forwarded_headers: ForwardedHeaders) -> Result<Self, ConfigError> {
+        let upstreams = build_upstreams(&config.upstreams)?;
+        let routes = config.routes
+            .iter()
+            .map(|route| Route::new(route, &upstreams, default_forwarded_headers).map(Arc::new))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(RouteTable {
+            routes,
+            upstreams: upstreams.into_values().collect(),
+        })
+    }
+
+    pub fn upstreams(&self) -> &[Arc<Upstream>] {
+        &self.upstreams
+    }
+
+    /// The first matching HTTP or WebSocket route.
+    pub fn find<H: HeaderLookup>(&self, req: &RouteRequest<H>) -> Option<Arc<Route>> {
+        self.routes.iter().find(|route| route.kind != RouteKind::Grpc && route.matches(req)).cloned()
+    }
+
+    /// The first matching gRPC route, for requests on the gRPC listener.
+    pub fn find_grpc<H: HeaderLookup>(&self, req: &RouteRequest<H>) -> Option<Arc<Route>> {
+        self.routes.iter().find(|route| route.kind == RouteKind::Grpc && route.matches(req)).cloned()
+    }
+}
+
+/// Whether `path` is `prefix` or lies below it, so `/ws` matches `/ws/feed` but not `/wsx`.
+fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/') || prefix.ends_with('/'),
+        None => false,
+    }
+}
+
+fn strip_port(host: &str) -> &str {
+    if let Some(end) = host.find(']') {
+        return &host[..=end];
+    }
+    host.split(':').next().unwrap_or(host)
+}
+
+/// Exact match, or suffix match for `*.example.com` patterns.
+fn host_matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix("*.") {
+        Some(domain) => host.len() > domain.len() && host.ends_with(domain) && host[..host.len() - domain.len()].ends_with('.'),
+        None => pattern == host,
+    }
+}
@@ -0,0 +1,357 @@
+use actix_tls::accept::rustls_0_23::TlsStream;
+use actix_web::dev::Extensions;
+use actix_web::rt::net::TcpStream;
+use log::{debug, error, info};
+use ring::digest::{digest, SHA256};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::client::WebPkiServerVerifier;
+use rustls::crypto::ring::{default_provider, sign::any_supported_type};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, WebPkiClientVerifier};
+use rustls::sign::CertifiedKey;
+use rustls::version::{TLS12, TLS13};
+use rustls::{ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme, SupportedProtocolVersion};
+use std::any::Any;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::time::{interval_at, Instant};
+use x509_parser::prelude::{oid2abbrev, oid_registry, FromDer, X509Certificate, X509Name};
+use crate::config::{CertificateConfig, ClientAuthConfig, ConfigError, TlsConfig, TlsVersion, UpstreamTlsConfig};
+
+/// Picks the certificate for each TLS handshake from the server name the
+/// client asks for, and swaps in new certificates when their files change.
+#[derive(Debug)]
+pub struct CertificateResolver {
+    files: Vec<CertificateConfig>,
+    certificates: RwLock<Arc<Vec<Arc<CertifiedKey>>>>,
+    /// Modification times of the files when they were last read.
+    modified: Mutex<Vec<Option<SystemTime>>>,
+}
+
+impl CertificateResolver {
+    pub fn new(files: Vec<CertificateConfig>) -> Result<Self, ConfigError> {
+        let modified = modified(&files);
+        let certificates = load_certificates(&files)?;
+        Ok(CertificateResolver {
+            files,
+            certificates: RwLock::new(Arc::new(certificates)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reads every certificate and key again. On error the current
+    /// certificates stay in use.
+    pub fn reload(&self) -> Result<(), ConfigError> {
+        let certificates = load_certificates(&self.files)?;
+        *self.certificates.write().unwrap() = Arc::new(certificates);
+        Ok(())
+    }
+
+    /// The first certificate valid for `server_name`, falling back to the
+    /// first certificate configured.
+    pub fn certificate_for(&self, server_name: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let certificates = Arc::clone(&self.certificates.read().unwrap());
+        server_name
+            .and_then(|name| certificates.iter().find(|certificate| is_valid_for(certificate, name)))
+            .or_else(|| certificates.first())
+            .cloned()
+    }
+
+    /// Reloads the certificates whenever one of their files has changed,
+    /// checking every `reload_interval`.
+    pub async fn watch(self: Arc<Self>, reload_interval: Duration) {
+        let mut ticker = interval_at(Instant::now() + reload_interval, reload_interval);
+
+        loop {
+            ticker.tick().await;
+            let current = modified(&self.files);
+            let previous = std::mem::replace(&mut *self.modified.lock().unwrap(), current.clone());
+            if previous == current {
+                continue;
+            }
+
+            match self.reload() {
+                Ok(()) => info!("Reloaded {} TLS certificates", self.files.len()),
+                Err(e) => error!("Failed to reload TLS certificates, keeping the current ones: {}", e),
+            }
+        }
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        self.certificate_for(client_hello.server_name())
+    }
+}
+
+/// The verified certificate a client presented in the TLS handshake.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ClientCertificate {
+    /// SHA-256 of the DER certificate, in lowercase hex.
+    pub fingerprint: String,
+    /// RFC 4514 form, as printed by `openssl x509 -noout -subject -nameopt RFC2253`.
+    pub subject: String,
+}
+
+impl ClientCertificate {
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, certificate) = X509Certificate::from_der(der).ok()?;
+        Some(ClientCertificate {
+            fingerprint: sha256_hex(der),
+            subject: rfc4514(certificate.subject()),
+        })
+    }
+}
+
+/// Keeps the client certificate of a TLS connection in the connection's
+/// extensions, for `HttpServer::on_connect`.
+pub fn on_connect(connection: &dyn Any, extensions: &mut Extensions) {
+    let certificate = connection
+        .downcast_ref::<TlsStream<TcpStream>>()
+        .and_then(|stream| stream.get_ref().1.peer_certificates())
+        .and_then(|chain| chain.first())
+        .and_then(|der| ClientCertificate::from_der(der));
+    if let Some(certificate) = certificate {
+        debug!("TLS client certificate {} ({})", certificate.subject, certificate.fingerprint);
+        extensions.insert(certificate);
+    }
+}
+
+/// Server settings for the TLS listener, limited to the TLS versions the
+/// configuration allows.
+pub fn server_config(config: &TlsConfig, resolver: Arc<CertificateResolver>) -> Result<ServerConfig, ConfigError> {
+    let versions: &[&SupportedProtocolVersion] = match config.min_version {
+        TlsVersion::Tls12 => &[&TLS13, &TLS12],
+        TlsVersion::Tls13 => &[&TLS13],
+    };
+    let builder = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_protocol_versions(versions)
+        .map_err(|e| ConfigError::Invalid(format!("tls: {}", e)))?;
+    let builder = match &config.client_auth {
+        Some(client_auth) => builder.with_client_cert_verifier(client_verifier(client_auth)?),
+        None => builder.with_no_client_auth(),
+    };
+    Ok(builder.with_cert_resolver(resolver))
+}
+
+fn client_verifier(config: &ClientAuthConfig) -> Result<Arc<dyn ClientCertVerifier>, ConfigError> {
+    let roots = load_roots(&config.ca)?;
+    let builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), Arc::new(default_provider()));
+    let builder = if config.required { builder } else { builder.allow_unauthenticated() };
+    builder.build().map_err(|e| ConfigError::Invalid(format!("tls: client_auth: {}", e)))
+}
+
+/// Client settings for connections to an upstream: its CA bundle (or the
+/// public web roots), its client certificate and its key pins.
+pub fn upstream_client_config(config: &UpstreamTlsConfig) -> Result<ClientConfig, ConfigError> {
+    let roots = match &config.ca {
+        Some(ca) => load_roots(ca)?,
+        None => RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() },
+    };
+    let provider = Arc::new(default_provider());
+    let verifier = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider.clone())
+        .build()
+        .map_err(|e| ConfigError::Invalid(format!("tls: {}", e)))?;
+    let pins = config.pins
+        .iter()
+        .map(|pin| match normalize_fingerprint(pin) {
+            pin if pin.len() == 64 && pin.bytes().all(|b| b.is_ascii_hexdigit()) => Ok(pin),
+            _ => Err(ConfigError::Invalid(format!("tls: invalid pin '{}'", pin))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let builder = ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| ConfigError::Invalid(format!("tls: {}", e)))?;
+    let builder = if pins.is_empty() {
+        builder.with_webpki_verifier(verifier)
+    } else {
+        builder.dangerous().with_custom_certificate_verifier(Arc::new(PinnedVerifier { verifier, pins }))
+    };
+
+    match (&config.cert, &config.key) {
+        (Some(cert), Some(key)) => {
+            let (chain, key) = load_key_pair(&CertificateConfig { cert: cert.clone(), key: key.clone() })?;
+            builder
+                .with_client_auth_cert(chain, key)
+                .map_err(|e| ConfigError::Invalid(format!("tls: {}", e)))
+        }
+        (None, None) => Ok(builder.with_no_client_auth()),
+        _ => Err(ConfigError::Invalid("tls: set both cert and key for a client certificate".to_string())),
+    }
+}
+
+/// Verifies upstream certificates as usual, then requires one of the
+/// certificates in the chain to carry a pinned public key.
+#[derive(Debug)]
+struct PinnedVerifier {
+    verifier: Arc<WebPkiServerVerifier>,
+    pins: Vec<String>,
+}
+
+impl ServerCertVerifier for PinnedVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.verifier.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now)?;
+        let pinned = std::iter::once(end_entity)
+            .chain(intermediates)
+            .filter_map(|der| X509Certificate::from_der(der).ok())
+            .any(|(_, certificate)| self.pins.contains(&sha256_hex(certificate.public_key().raw)));
+        if pinned {
+            Ok(verified)
+        } else {
+            Err(rustls::Error::General("upstream certificate does not match any pinned key".to_string()))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.verifier.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.verifier.supported_verify_schemes()
+    }
+}
+
+/// Lowercase hex without colons, as `openssl -fingerprint` output is often pasted.
+pub(crate) fn normalize_fingerprint(fingerprint: &str) -> String {
+    fingerprint.chars().filter(|&c| c != ':').collect::<String>().to_ascii_lowercase()
+}
+
+fn sha256_hex(bytes: &[u8]) -> String {
+    digest(&SHA256, bytes).as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn load_roots(path: &str) -> Result<RootCertStore, ConfigError> {
+    let mut roots = RootCertStore::empty();
+    for certificate in rustls_pemfile::certs(&mut open(path)?) {
+        let certificate = certificate.map_err(|e| ConfigError::File(path.to_string(), e.to_string()))?;
+        roots.add(certificate).map_err(|e| ConfigError::File(path.to_string(), e.to_string()))?;
+    }
+    if roots.is_empty() {
+        return Err(ConfigError::File(path.to_string(), "no CA certificates found".to_string()));
+    }
+    Ok(roots)
+}
+
+fn load_certificates(files: &[CertificateConfig]) -> Result<Vec<Arc<CertifiedKey>>, ConfigError> {
+    if files.is_empty() {
+        return Err(ConfigError::Invalid("tls: no certificates".to_string()));
+    }
+    files.iter().map(load_certificate).collect()
+}
+
+fn load_certificate(files: &CertificateConfig) -> Result<Arc<CertifiedKey>, ConfigError> {
+    let (chain, key) = load_key_pair(files)?;
+    let key = any_supported_type(&key).map_err(|e| ConfigError::File(files.key.clone(), e.to_string()))?;
+    Ok(Arc::new(CertifiedKey::new(chain, key)))
+}
+
+/// Reads a certificate chain and its private key, checking that they belong together.
+fn load_key_pair(
+    files: &CertificateConfig,
+) -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), ConfigError> {
+    let file_error = |path: &str, message: String| ConfigError::File(path.to_string(), message);
+
+    let chain = rustls_pemfile::certs(&mut open(&files.cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| file_error(&files.cert, e.to_string()))?;
+    if chain.is_empty() {
+        return Err(file_error(&files.cert, "no certificates found".to_string()));
+    }
+    let key = rustls_pemfile::private_key(&mut open(&files.key)?)
+        .map_err(|e| file_error(&files.key, e.to_string()))?
+        .ok_or_else(|| file_error(&files.key, "no private key found".to_string()))?;
+
+    let signing_key = any_supported_type(&key).map_err(|e| file_error(&files.key, e.to_string()))?;
+    CertifiedKey::new(chain.clone(), signing_key).keys_match().map_err(|e| {
+        ConfigError::Invalid(format!("tls: {} does not belong to {}: {}", files.key, files.cert, e))
+    })?;
+    Ok((chain, key))
+}
+
+fn modified(files: &[CertificateConfig]) -> Vec<Option<SystemTime>> {
+    files
+        .iter()
+        .flat_map(|files| [&files.cert, &files.key])
+        .map(|path| fs::metadata(path).and_then(|metadata| metadata.modified()).ok())
+        .collect()
+}
+
+fn open(path: &str) -> Result<BufReader<File>, ConfigError> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|e| ConfigError::File(path.to_string(), e.to_string()))
+}
+
+fn is_valid_for(certificate: &CertifiedKey, name: &str) -> bool {
+    let name = match ServerName::try_from(name) {
+        Ok(name) => name,
+        Err(_) => return false,
+    };
+    certificate
+        .end_entity_cert()
+        .ok()
+        .and_then(|der| webpki::EndEntityCert::try_from(der).ok())
+        .is_some_and(|cert| cert.verify_is_valid_for_subject_name(&name).is_ok())
+}
+
+/// Formats a distinguished name as in RFC 4514: last RDN first, attributes
+/// by their short names, special characters escaped.
+fn rfc4514(name: &X509Name) -> String {
+    let registry = oid_registry();
+    let rdns: Vec<_> = name.iter_rdn().collect();
+    rdns.iter()
+        .rev()
+        .map(|rdn| {
+            rdn.iter()
+                .map(|attribute| {
+                    let oid = attribute.attr_type();
+                    let key = oid2abbrev(oid, registry).map(str::to_string).unwrap_or_else(|_| oid.to_id_string());
+                    format!("{}={}", key, escape_rfc4514(attribute.as_str().unwrap_or_default()))
+                })
+                .collect::<Vec<_>>()
+                .join("+")
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+fn escape_rfc4514(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::with_capacity(value.len());
+    for (index, c) in value.chars().enumerate() {
+        let at_edge = index == 0 || index == last;
+        if matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+            || (index == 0 && c == '#')
+            || (at_edge && c == ' ')
+        {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
@@ -0,0 +1,370 @@
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use crate::circuit_breaker::CircuitBreaker;
+use crate::config::{ConfigError, EndpointConfig, HealthCheckConfig, PassiveHealthCheckConfig, UpstreamConfig};
+use crate::health::EndpointHealth;
+use crate::tls;
+
+/// Points each endpoint gets on the consistent hash ring, per unit of weight.
+const VIRTUAL_NODES: u32 = 100;
+
+/// How an upstream spreads requests over its endpoints.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoadBalancing {
+    #[default]
+    RoundRobin,
+    /// Fewest in-flight requests and WebSocket connections relative to weight.
+    LeastConnections,
+    Weighted,
+    /// Pins each API key to an endpoint, moving few keys when endpoints change.
+    ConsistentHash,
+}
+
+/// HTTP version spoken to an upstream's endpoints. WebSocket connections
+/// always use HTTP/1.1.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpVersion {
+    /// HTTP/2 when the endpoint offers it through TLS ALPN, HTTP/1.1 otherwise.
+    #[default]
+    Auto,
+    Http1,
+    /// HTTP/2 only: negotiated with ALPN over TLS and with prior knowledge
+    /// (h2c) over plain connections.
+    Http2,
+}
+
+impl HttpVersion {
+    fn alpn_protocols(self) -> Vec<Vec<u8>> {
+        match self {
+            HttpVersion::Auto => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            HttpVersion::Http1 => vec![b"http/1.1".to_vec()],
+            HttpVersion::Http2 => vec![b"h2".to_vec()],
+        }
+    }
+}
+
+/// One instance of an upstream service.
+#[derive(Debug)]
+pub struct Endpoint {
+    pub url: String,
+    /// `url` with the host replaced by the upstream's TLS `server_name`, if
+    /// any. Requests are addressed here but connect to `host` and `port`.
+    pub request_url: String,
+    pub host: String,
+    pub port: u16,
+    pub weight: u32,
+    /// Position in `Upstream::endpoints`.
+    pub(crate) index: usize,
+    active: AtomicUsize,
+    pub(crate) health: EndpointHealth,
+}
+
+impl Endpoint {
+    fn new(
+        index: usize,
+        config: &EndpointConfig,
+        passive: Option<PassiveHealthCheckConfig>,
+        server_name: Option<&str>,
+    ) -> Result<Self, String> {
+        let url = config.url.trim_end_matches('/').to_string();
+        let mut parsed = Url::parse(&url).map_err(|e| format!("invalid endpoint url '{}': {}", url, e))?;
+        let host = parsed.host_str()
+            .ok_or_else(|| format!("endpoint url '{}' has no host", url))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = parsed.port_or_known_default()
+            .ok_or_else(|| format!("endpoint url '{}' has no port", url))?;
+        let request_url = match server_name {
+            Some(server_name) => {
+                parsed.set_host(Some(server_name))
+                    .map_err(|e| format!("invalid server_name '{}': {}", server_name, e))?;
+                parsed.as_str().trim_end_matches('/').to_string()
+            }
+            None => url.clone(),
+        };
+
+        Ok(Endpoint {
+            url,
+            request_url,
+            host,
+            port,
+            weight: config.weight,
+            index,
+            active: AtomicUsize::new(0),
+            health: EndpointHealth::new(passive),
+        })
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+}
+
+/// An endpoint chosen for one request or WebSocket connection, counted as
+/// active until dropped.
+#[derive(Debug)]
+pub struct EndpointGuard {
+    endpoint: Arc<Endpoint>,
+}
+
+impl EndpointGuard {
+    fn new(endpoint: &Arc<Endpoint>) -> Self {
+        endpoint.active.fetch_add(1, Ordering::Relaxed);
+        EndpointGuard { endpoint: Arc::clone(endpoint) }
+    }
+
+    pub fn endpoint(&self) -> &Arc<Endpoint> {
+        &self.endpoint
+    }
+}
+
+impl Deref for EndpointGuard {
+    type Target = Endpoint;
+
+    fn deref(&self) -> &Endpoint {
+        &self.endpoint
+    }
+}
+
+impl Drop for EndpointGuard {
+    fn drop(&mut self) {
+        self.endpoint.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Why no endpoint could be selected for a request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Unavailable {
+    CircuitOpen,
+    NoHealthyEndpoint,
+}
+
+impl std::fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Unavailable::CircuitOpen => write!(f, "circuit open"),
+            Unavailable::NoHealthyEndpoint => write!(f, "no healthy endpoint"),
+        }
+    }
+}
+
+/// A named backend service made of one or more endpoints.
+#[derive(Debug)]
+pub struct Upstream {
+    pub name: String,
+    pub strategy: LoadBalancing,
+    pub http_version: HttpVersion,
+    pub health_check: Option<HealthCheckConfig>,
+    /// Client TLS settings for `https://` and `wss://` endpoints: the
+    /// configured ones, or the public web roots without a `tls` section.
+    pub tls: Arc<rustls::ClientConfig>,
+    circuit_breaker: Option<CircuitBreaker>,
+    endpoints: Vec<Arc<Endpoint>>,
+    next: AtomicUsize,
+    ring: Vec<(u64, usize)>,
+}
+
+impl Upstream {
+    pub fn new(name: &str, config: &UpstreamConfig) -> Result<Self, ConfigError> {
+        let invalid = |message: &str| ConfigError::Invalid(format!("upstream '{}': {}", name, message));
+
+        let server_name = config.tls.as_ref().and_then(|tls| tls.server_name.as_deref());
+        let endpoints: Vec<Arc<Endpoint>> = match (&config.url, config.endpoints.is_empty()) {
+            (Some(url), true) => vec![EndpointConfig { url: url.clone(), weight: 1 }],
+            (None, false) => config.endpoints.clone(),
+            (Some(_), false) => return Err(invalid("set either url or endpoints, not both")),
+            (None, true) => return Err(invalid("no endpoints")),
+        }
+        .iter()
+        .enumerate()
+        .map(|(index, e)| Endpoint::new(index, e, config.passive_health_check, server_name).map(Arc::new))
+        .collect::<Result<_, _>>()
+        .map_err(|message| invalid(&message))?;
+        if endpoints.iter().any(|e| e.weight == 0) {
+            return Err(invalid("endpoint weights must be at least 1"));
+        }
+
+        let mut ring = Vec::new();
+        if config.strategy == LoadBalancing::ConsistentHash {
+            for (index, endpoint) in endpoints.iter().enumerate() {
+                for node in 0..VIRTUAL_NODES * endpoint.weight {
+                    ring.push((hash(format!("{}#{}", endpoint.url, node).as_bytes()), index));
+                }
+            }
+            ring.sort_unstable();
+        }
+
+        let tls = tls::upstream_client_config(&config.tls.clone().unwrap_or_default())
+            .map(Arc::new)
+            .map_err(|e| invalid(&e.to_string()))?;
+
+        Ok(Upstream {
+            name: name.to_string(),
+            strategy: config.strategy,
+            http_version: config.http_version,
+            health_check: config.health_check.clone(),
+            tls,
+            circuit_breaker: config.circuit_breaker.map(|c| CircuitBreaker::new(name, c)),
+            endpoints,
+            next: AtomicUsize::new(0),
+            ring,
+        })
+    }
+
+    pub fn endpoints(&self) -> &[Arc<Endpoint>] {
+        &self.endpoints
+    }
+
+    pub fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        self.circuit_breaker.as_ref()
+    }
+
+    /// A client builder for requests to `endpoint`, with the upstream's HTTP
+    /// version and TLS settings, offering HTTP/2 through ALPN unless it is
+    /// `http1`. With a `server_name`, names are resolved to
+    /// the endpoint's host, so requests to its `request_url` still reach it.
+    pub fn client_builder(&self, endpoint: &Endpoint) -> reqwest::ClientBuilder {
+        let mut builder = match self.http_version {
+            HttpVersion::Auto => reqwest::Client::builder(),
+            HttpVersion::Http1 => reqwest::Client::builder().http1_only(),
+            HttpVersion::Http2 => reqwest::Client::builder().http2_prior_knowledge(),
+        };
+        if endpoint.request_url != endpoint.url {
+            builder = builder.dns_resolver(Arc::new(EndpointResolver { host: endpoint.host.clone() }));
+        }
+        let mut tls = rustls::ClientConfig::clone(&self.tls);
+        tls.alpn_protocols = self.http_version.alpn_protocols();
+        builder.use_preconfigured_tls(tls)
+    }
+
+    /// Picks an available endpoint for a request, failing when every endpoint
+    /// is unhealthy or ejected, or when the circuit is open. `hash_key` (the
+    /// API key id) is only used by `consistent_hash`; without one it falls
+    /// back to round-robin.
+    pub fn select(&self, hash_key: Option<i32>) -> Result<EndpointGuard, Unavailable> {
+        let index = match (self.strategy, hash_key) {
+            (LoadBalancing::RoundRobin, _) | (LoadBalancing::ConsistentHash, None) => self.round_robin(),
+            (LoadBalancing::Weighted, _) => self.weighted(),
+            (LoadBalancing::LeastConnections, _) => self.least_connections(),
+            (LoadBalancing::ConsistentHash, Some(key)) => self.consistent_hash(key),
+        }
+        .ok_or(Unavailable::NoHealthyEndpoint)?;
+        if let Some(breaker) = &self.circuit_breaker {
+            if !breaker.try_acquire() {
+                return Err(Unavailable::CircuitOpen);
+            }
+        }
+        Ok(EndpointGuard::new(&self.endpoints[index]))
+    }
+
+    /// Records a request `endpoint` answered without a `5xx`.
+    pub fn record_success(&self, endpoint: &Endpoint) {
+        endpoint.record_success();
+        if let Some(breaker) = &self.circuit_breaker {
+            breaker.record_success();
+        }
+    }
+
+    /// Records a connection error, timeout or `5xx` from `endpoint`.
+    pub fn record_failure(&self, endpoint: &Endpoint) {
+        endpoint.record_failure();
+        if let Some(breaker) = &self.circuit_breaker {
+            breaker.record_failure();
+        }
+    }
+
+    /// Available endpoint indices, starting from a rotating position so ties
+    /// are spread across endpoints.
+    fn rotation(&self) -> impl Iterator<Item = usize> + '_ {
+        let count = self.endpoints.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed) % count;
+        (0..count)
+            .map(move |offset| (start + offset) % count)
+            .filter(|&index| self.endpoints[index].is_available())
+    }
+
+    fn round_robin(&self) -> Option<usize> {
+        self.rotation().next()
+    }
+
+    fn weighted(&self) -> Option<usize> {
+        let available: Vec<usize> = (0..self.endpoints.len())
+            .filter(|&index| self.endpoints[index].is_available())
+            .collect();
+        let total_weight: usize = available.iter().map(|&index| self.endpoints[index].weight as usize).sum();
+        if total_weight == 0 {
+            return None;
+        }
+
+        let mut slot = self.next.fetch_add(1, Ordering::Relaxed) % total_weight;
+        for index in available {
+            let weight = self.endpoints[index].weight as usize;
+            if slot < weight {
+                return Some(index);
+            }
+            slot -= weight;
+        }
+        None
+    }
+
+    fn least_connections(&self) -> Option<usize> {
+        let load = |index: usize| {
+            let endpoint = &self.endpoints[index];
+            (endpoint.active_connections() as u64, endpoint.weight as u64)
+        };
+        self.rotation().min_by(|&a, &b| {
+            let ((active_a, weight_a), (active_b, weight_b)) = (load(a), load(b));
+            (active_a * weight_b).cmp(&(active_b * weight_a))
+        })
+    }
+
+    /// Walks the ring clockwise from the key's point to the first available endpoint.
+    fn consistent_hash(&self, key: i32) -> Option<usize> {
+        let point = hash(&key.to_le_bytes());
+        let position = self.ring.partition_point(|&(p, _)| p < point);
+        (0..self.ring.len())
+            .map(|offset| self.ring[(position + offset) % self.ring.len()].1)
+            .find(|&index| self.endpoints[index].is_available())
+    }
+}
+
+/// Resolves every name to one endpoint's host.
+struct EndpointResolver {
+    host: String,
+}
+
+impl Resolve for EndpointResolver {
+    fn resolve(&self, _: Name) -> Resolving {
+        let host = self.host.clone();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+pub fn build_upstreams(configs: &HashMap<String, UpstreamConfig>) -> Result<HashMap<String, Arc<Upstream>>, ConfigError> {
+    configs
+        .iter()
+        .map(|(name, config)| Ok((name.clone(), Arc::new(Upstream::new(name, config)?))))
+        .collect()
+}
+
+/// 64-bit FNV-1a followed by the SplitMix64 finalizer, which spreads
+/// similar inputs over the ring. Fixed so that every proxy instance, whatever
+/// it was built with, maps an API key to the same endpoint.
+fn hash(bytes: &[u8]) -> u64 {
+    let mut h = bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |h, &b| (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3));
+    h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    h ^ (h >> 31)
+}
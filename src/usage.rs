@@ -0,0 +1,99 @@
+use log::{debug, error};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio_postgres::Client;
+use crate::db::{self, ApiKey};
+
+/// Request counts accumulated in memory between two flushes, keyed by
+/// `(api_key_id, product_id)`.
+#[derive(Default)]
+pub struct UsageRecorder {
+    counts: Mutex<HashMap<(i32, Option<i32>), i64>>,
+}
+
+impl UsageRecorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&self, api_key: &ApiKey) {
+        let mut counts = self.counts.lock().unwrap();
+        *counts.entry((api_key.id, api_key.product_id)).or_insert(0) += 1;
+    }
+
+    fn take(&self) -> HashMap<(i32, Option<i32>), i64> {
+        std::mem::take(&mut *self.counts.lock().unwrap())
+    }
+
+    fn restore(&self, pending: HashMap<(i32, Option<i32>), i64>) {
+        let mut counts = self.counts.lock().unwrap();
+        for (key, count) in pending {
+            *counts.entry(key).or_insert(0) += count;
+        }
+    }
+}
+
+/// Writes recorded usage into `usage`, creating and rolling over `periods` as needed.
+pub struct UsageFlusher {
+    client: Arc<Client>,
+    recorder: Arc<UsageRecorder>,
+    period_length: Duration,
+    periods: HashMap<Option<i32>, (i32, SystemTime)>,
+}
+
+impl UsageFlusher {
+    pub fn new(client: Arc<Client>, recorder: Arc<UsageRecorder>, period_length: Duration) -> Self {
+        UsageFlusher {
+            client,
+            recorder,
+            period_length,
+            periods: HashMap::new(),
+        }
+    }
+
+    /// Flushes every `interval` until the task is dropped.
+    pub async fn run(mut self, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            self.flush().await;
+        }
+    }
+
+    /// Flushes the counts recorded so far. Counts that could not be written
+    /// are put back into the recorder and retried on the next flush.
+    pub async fn flush(&mut self) {
+        let pending = self.recorder.take();
+        if pending.is_empty() {
+            return;
+        }
+
+        let now = SystemTime::now();
+        let mut failed = HashMap::new();
+        for ((api_key_id, product_id), count) in pending {
+            if let Err(e) = self.write(api_key_id, product_id, count, now).await {
+                error!("Failed to flush usage for API key {}: {}", api_key_id, e);
+                failed.insert((api_key_id, product_id), count);
+            }
+        }
+
+        if !failed.is_empty() {
+            self.recorder.restore(failed);
+        }
+    }
+
+    async fn write(&mut self, api_key_id: i32, product_id: Option<i32>, count: i64, now: SystemTime) -> Result<(), tokio_postgres::Error> {
+        let period_id = match self.periods.get(&product_id) {
+            Some((id, date_end)) if *date_end > now => *id,
+            _ => {
+                let period = db::current_period(&self.client, product_id, now, self.period_length).await?;
+                debug!("Using period {} for product {:?}", period.0, product_id);
+                self.periods.insert(product_id, period);
+                period.0
+            }
+        };
+
+        db::add_usage(&self.client, api_key_id, period_id, count).await
+    }
+}
@@ -0,0 +1,81 @@
+use reverse_proxy::circuit_breaker::{CircuitBreaker, CircuitState};
+use reverse_proxy::config::{CircuitBreakerConfig, UpstreamConfig};
+use reverse_proxy::upstream::{Unavailable, Upstream};
+
+fn config(open_seconds: u64) -> CircuitBreakerConfig {
+    CircuitBreakerConfig {
+        failure_ratio: 0.5,
+        min_requests: 4,
+        window_seconds: 60,
+        open_seconds,
+        half_open_requests: 2,
+    }
+}
+
+#[test]
+fn test_opens_on_failure_ratio() {
+    let breaker = CircuitBreaker::new("backend", config(60));
+
+    // Too few requests for the ratio to apply
+    breaker.record_failure();
+    breaker.record_failure();
+    breaker.record_failure();
+    assert_eq!(breaker.state(), CircuitState::Closed);
+
+    breaker.record_success();
+    assert_eq!(breaker.state(), CircuitState::Open);
+    assert!(!breaker.try_acquire());
+}
+
+#[test]
+fn test_stays_closed_below_ratio() {
+    let breaker = CircuitBreaker::new("backend", config(60));
+    for _ in 0..10 {
+        breaker.record_success();
+        breaker.record_success();
+        breaker.record_failure();
+    }
+    assert_eq!(breaker.state(), CircuitState::Closed);
+}
+
+#[test]
+fn test_half_open_trials() {
+    let breaker = CircuitBreaker::new("backend", config(0));
+    for _ in 0..4 {
+        breaker.record_failure();
+    }
+    assert_eq!(breaker.state(), CircuitState::Open);
+
+    // The cool-down is over, so two trial requests are let through
+    assert!(breaker.try_acquire());
+    assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    assert!(breaker.try_acquire());
+
+    breaker.record_success();
+    assert_eq!(breaker.state(), CircuitState::HalfOpen);
+    breaker.record_success();
+    assert_eq!(breaker.state(), CircuitState::Closed);
+
+    for _ in 0..4 {
+        breaker.record_failure();
+    }
+    assert!(breaker.try_acquire());
+    breaker.record_failure();
+    assert_eq!(breaker.state(), CircuitState::Open);
+}
+
+#[test]
+fn test_upstream_fails_fast_when_open() {
+    let config = UpstreamConfig {
+        url: Some("http://backend".to_string()),
+        circuit_breaker: Some(config(60)),
+        ..Default::default()
+    };
+    let upstream = Upstream::new("backend", &config).unwrap();
+
+    for _ in 0..4 {
+        let endpoint = upstream.select(None).unwrap();
+        upstream.record_failure(&endpoint);
+    }
+    assert_eq!(upstream.select(None).unwrap_err(), Unavailable::CircuitOpen);
+}
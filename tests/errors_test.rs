@@ -0,0 +1,80 @@
+use actix_web::body::to_bytes;
+use actix_web::http::StatusCode;
+use reverse_proxy::config::ErrorConfig;
+use reverse_proxy::errors::{request_id, ErrorResponses, ProxyError};
+use std::collections::HashMap;
+
+async fn render(responses: &ErrorResponses, error: ProxyError, request_id: &str) -> (StatusCode, String, String) {
+    let response = responses.response(error, request_id);
+    let status = response.status();
+    let content_type = response.headers().get("content-type").unwrap().to_str().unwrap().to_string();
+    let body = to_bytes(response.into_body()).await.unwrap();
+    (status, content_type, String::from_utf8(body.to_vec()).unwrap())
+}
+
+#[actix_rt::test]
+async fn test_default_json_errors() {
+    let responses = ErrorResponses::default();
+    let (status, content_type, body) = render(&responses, ProxyError::UpstreamTimeout, "abc123").await;
+
+    assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    assert_eq!(content_type, "application/json");
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(json["error"]["code"], "upstream_timeout");
+    assert_eq!(json["error"]["request_id"], "abc123");
+}
+
+#[test]
+fn test_error_statuses() {
+    assert_eq!(ProxyError::InvalidApiKey.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(ProxyError::RateLimited.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(ProxyError::CircuitOpen.status(), StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(ProxyError::UpstreamUnreachable.status(), StatusCode::BAD_GATEWAY);
+    assert_eq!(ProxyError::UpstreamTimeout.status(), StatusCode::GATEWAY_TIMEOUT);
+}
+
+#[actix_rt::test]
+async fn test_custom_templates() {
+    let config = ErrorConfig {
+        messages: HashMap::from([("rate_limited".to_string(), "Slow \"down\"".to_string())]),
+        templates: HashMap::from([("no_route".to_string(), "{\"status\":{status}}".to_string())]),
+        ..Default::default()
+    };
+    let responses = ErrorResponses::new(&config).unwrap();
+
+    // Messages are escaped for JSON templates
+    let (_, _, body) = render(&responses, ProxyError::RateLimited, "id").await;
+    let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(json["error"]["message"], "Slow \"down\"");
+
+    let (_, _, body) = render(&responses, ProxyError::NoRoute, "id").await;
+    assert_eq!(body, "{\"status\":404}");
+
+    let plain = ErrorConfig {
+        content_type: "text/plain".to_string(),
+        template: "{code}: {message} ({request_id})".to_string(),
+        ..Default::default()
+    };
+    let (_, content_type, body) = render(&ErrorResponses::new(&plain).unwrap(), ProxyError::MissingApiKey, "r1").await;
+    assert_eq!(content_type, "text/plain");
+    assert_eq!(body, "missing_api_key: Missing API key (r1)");
+}
+
+#[test]
+fn test_unknown_error_codes_are_rejected() {
+    let config = ErrorConfig {
+        messages: HashMap::from([("teapot".to_string(), "I'm a teapot".to_string())]),
+        ..Default::default()
+    };
+    assert!(ErrorResponses::new(&config).is_err());
+}
+
+#[test]
+fn test_request_ids() {
+    assert_eq!(request_id(Some("req-42.a_b")), "req-42.a_b");
+
+    let generated = request_id(Some("bad id\"}"));
+    assert_eq!(generated.len(), 32);
+    assert!(generated.bytes().all(|b| b.is_ascii_hexdigit()));
+    assert_ne!(request_id(None), request_id(None));
+}
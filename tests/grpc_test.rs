@@ -0,0 +1,216 @@
+use http::{HeaderMap, Request, Response};
+use http_body_util::{BodyExt, StreamBody};
+use hyper::body::{Bytes, Frame, Incoming};
+use hyper::server::conn::http2;
+use hyper::service::service_fn;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use reverse_proxy::config::{Config, GrpcConfig, ProxyConfig};
+use reverse_proxy::db::ApiKey;
+use reverse_proxy::errors::{ErrorResponses, ProxyError};
+use reverse_proxy::handlers::grpc::{self, percent_encode};
+use reverse_proxy::handlers::headers::ForwardedHeaders;
+use reverse_proxy::keys::KeyStore;
+use reverse_proxy::middleware::Middleware;
+use reverse_proxy::rate_limit::{RateLimitAlgorithm, RateLimitKey, RateLimitSettings, RedisFailurePolicy};
+use reverse_proxy::routing::{RouteKind, RouteRequest, RouteTable};
+use reverse_proxy::usage::UsageRecorder;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+
+const API_KEY: &str = "grpc-key";
+
+fn routes(upstream_url: &str) -> String {
+    format!(
+        r#"
+        [upstreams.echo]
+        url = "{}"
+
+        [upstreams.dead]
+        url = "http://127.0.0.1:1"
+
+        [[routes]]
+        name = "dead"
+        upstream = "dead"
+        kind = "grpc"
+        path_prefix = "/dead.v1.Dead"
+
+        [[routes]]
+        name = "echo"
+        upstream = "echo"
+        kind = "grpc"
+        path_prefix = "/echo.v1.Echo"
+
+        [[routes]]
+        name = "http"
+        upstream = "echo"
+        "#,
+        upstream_url
+    )
+}
+
+fn config(proxy: ProxyConfig, requests_per_minute: u32) -> Config {
+    let settings = |key| RateLimitSettings { algorithm: RateLimitAlgorithm::FixedWindow, key, burst: None };
+    Config {
+        database_url: String::new(),
+        port: 0,
+        ws_port: 0,
+        proxy,
+        forwarded_headers: ForwardedHeaders::Both,
+        http_requests_per_minute: requests_per_minute,
+        ws_connections_per_minute: requests_per_minute,
+        http_rate_limit: settings(RateLimitKey::ApiKey),
+        ws_rate_limit: settings(RateLimitKey::ApiKey),
+        quota_rate_limit: settings(RateLimitKey::ApiKey),
+        redis_url: "redis://127.0.0.1:1".to_string(),
+        redis_failure_policy: RedisFailurePolicy::LocalFallback,
+        usage_flush_interval_seconds: 10,
+        usage_period_seconds: 60,
+        api_keys_reload_seconds: 60,
+    }
+}
+
+/// An h2c upstream that echoes the request body, then sends `grpc-status: 0`
+/// and the consumer id and `TE` header it was given as trailers.
+async fn echo_upstream() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(|req: Request<Incoming>| async move {
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", "0".parse().unwrap());
+                trailers.insert("x-consumer-id", req.headers()["x-consumer-id"].clone());
+                trailers.insert("x-te", req.headers()["te"].clone());
+                let body = req.into_body().collect().await.unwrap().to_bytes();
+                let frames = vec![Ok::<_, Infallible>(Frame::data(body)), Ok(Frame::trailers(trailers))];
+                let response = Response::builder()
+                    .header("content-type", "application/grpc")
+                    .body(StreamBody::new(futures::stream::iter(frames)))
+                    .unwrap();
+                Ok::<_, Infallible>(response)
+            });
+            tokio::spawn(http2::Builder::new(TokioExecutor::new()).serve_connection(TokioIo::new(stream), service));
+        }
+    });
+    url
+}
+
+/// Starts the gRPC listener in front of `upstream_url` and returns its URL.
+async fn grpc_proxy(upstream_url: &str, requests_per_minute: u32) -> String {
+    let config = config(ProxyConfig::from_toml(&routes(upstream_url)).unwrap(), requests_per_minute);
+    let api_keys = HashMap::from([(API_KEY.to_string(), ApiKey { id: 7, user_id: None, product_id: None })]);
+    let middleware = Middleware::new(
+        Arc::new(KeyStore::new(api_keys, HashMap::new(), Vec::new())),
+        Arc::new(RouteTable::new(&config.proxy, config.forwarded_headers).unwrap()),
+        Arc::new(ErrorResponses::default()),
+        Arc::new(UsageRecorder::new()),
+        &config,
+    )
+    .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    tokio::spawn(grpc::serve(listener, None, middleware));
+    url
+}
+
+struct Call {
+    headers: HeaderMap,
+    body: Bytes,
+    trailers: Option<HeaderMap>,
+}
+
+async fn call(client: &reqwest::Client, url: &str, api_key: Option<&str>, content_type: &str) -> Call {
+    let mut request = client.post(url).header("content-type", content_type).body("\0\0\0\0\x02hi");
+    if let Some(api_key) = api_key {
+        request = request.header("x-api-key", api_key);
+    }
+    let response = Response::from(request.send().await.unwrap());
+    let (parts, body) = response.into_parts();
+    let collected = body.collect().await.unwrap();
+    Call { headers: parts.headers, trailers: collected.trailers().cloned(), body: collected.to_bytes() }
+}
+
+#[actix_rt::test]
+async fn test_grpc_calls_through_proxy() {
+    let proxy = grpc_proxy(&echo_upstream().await, 2).await;
+    let client = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+    let status = |call: &Call| call.headers.get("grpc-status").map(|v| v.to_str().unwrap().to_string());
+
+    // Trailers from the upstream reach the client
+    let ok = call(&client, &format!("{}/echo.v1.Echo/Say", proxy), Some(API_KEY), "application/grpc").await;
+    assert_eq!(ok.body, Bytes::from_static(b"\0\0\0\0\x02hi"));
+    let trailers = ok.trailers.unwrap();
+    assert_eq!(trailers["grpc-status"], "0");
+    assert_eq!(trailers["x-consumer-id"], "7");
+    assert_eq!(trailers["x-te"], "trailers");
+    assert!(ok.headers.contains_key("x-request-id"));
+    assert_eq!(ok.headers["ratelimit-limit"], "2");
+
+    // Failures are trailers-only responses with a gRPC status
+    let unauthenticated = call(&client, &format!("{}/echo.v1.Echo/Say", proxy), None, "application/grpc+proto").await;
+    assert_eq!(status(&unauthenticated).as_deref(), Some("16"));
+    assert_eq!(unauthenticated.headers["grpc-message"], "Missing API key");
+    assert!(unauthenticated.body.is_empty());
+
+    // HTTP routes are not served on the gRPC listener
+    let unimplemented = call(&client, &format!("{}/other.v1.Other/Get", proxy), Some(API_KEY), "application/grpc").await;
+    assert_eq!(status(&unimplemented).as_deref(), Some("12"));
+
+    let unavailable = call(&client, &format!("{}/dead.v1.Dead/Get", proxy), Some(API_KEY), "application/grpc").await;
+    assert_eq!(status(&unavailable).as_deref(), Some("14"));
+
+    // The successful and the unavailable call used up the limit of 2
+    let exhausted = call(&client, &format!("{}/echo.v1.Echo/Say", proxy), Some(API_KEY), "application/grpc").await;
+    assert_eq!(status(&exhausted).as_deref(), Some("8"));
+    assert!(exhausted.headers.contains_key("retry-after"));
+
+    let response = client.post(format!("{}/echo.v1.Echo/Say", proxy)).header("content-type", "application/json").send().await.unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::UNSUPPORTED_MEDIA_TYPE);
+}
+
+#[test]
+fn test_grpc_routes_only_match_on_grpc_listener() {
+    let config = ProxyConfig::from_toml(&routes("http://echo:50051")).unwrap();
+    let table = RouteTable::new(&config, ForwardedHeaders::Both).unwrap();
+    let headers = HeaderMap::new();
+    let request = RouteRequest { method: "POST", scheme: "http", host: None, path: "/echo.v1.Echo/Say", headers: &headers };
+
+    assert_eq!(table.find_grpc(&request).unwrap().kind, RouteKind::Grpc);
+    assert_eq!(table.find(&request).unwrap().name, "http");
+    assert!(table.find_grpc(&RouteRequest { path: "/other", ..request }).is_none());
+
+    // gRPC needs HTTP/2 to the upstream
+    let http1 = ProxyConfig::from_toml(
+        "[upstreams.echo]\nurl = \"http://echo\"\nhttp_version = \"http1\"\n[[routes]]\nname = \"echo\"\nupstream = \"echo\"\nkind = \"grpc\"\n",
+    )
+    .unwrap();
+    assert!(RouteTable::new(&http1, ForwardedHeaders::Both).is_err());
+}
+
+#[test]
+fn test_grpc_config() {
+    let config = ProxyConfig::from_toml("[grpc]\nport = 50051\n").unwrap();
+    let grpc_config = config.grpc.unwrap();
+    assert_eq!(grpc_config.port, 50051);
+    assert!(!grpc_config.tls);
+
+    assert!(grpc::tls_acceptor(&grpc_config, None).unwrap().is_none());
+    assert!(grpc::tls_acceptor(&GrpcConfig { port: 50051, tls: true }, None).is_err());
+}
+
+#[test]
+fn test_grpc_status_codes() {
+    assert_eq!(ProxyError::InvalidApiKey.grpc_status(), 16);
+    assert_eq!(ProxyError::InvalidClientCertificate.grpc_status(), 16);
+    assert_eq!(ProxyError::RateLimited.grpc_status(), 8);
+    assert_eq!(ProxyError::NoRoute.grpc_status(), 12);
+    assert_eq!(ProxyError::CircuitOpen.grpc_status(), 14);
+    assert_eq!(ProxyError::UpstreamTimeout.grpc_status(), 4);
+
+    assert_eq!(percent_encode("Rate limit exceeded"), "Rate limit exceeded");
+    assert_eq!(percent_encode("100% \"done\"\n"), "100%25 \"done\"%0A");
+    assert_eq!(percent_encode("café"), "caf%C3%A9");
+}
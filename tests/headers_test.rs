@@ -0,0 +1,68 @@
+use reverse_proxy::handlers::headers::{
+    forwarded, hop_by_hop_headers, identity_headers, x_forwarded_for, ForwardedHeaders, CLIENT_ONLY_HEADERS,
+};
+use reverse_proxy::ApiKey;
+use std::net::IpAddr;
+
+#[test]
+fn test_hop_by_hop_headers_include_connection_tokens() {
+    let connection: [&[u8]; 2] = [b"keep-alive, X-Internal-Token", b"close"];
+    let names = hop_by_hop_headers(connection.into_iter());
+
+    for name in ["connection", "keep-alive", "te", "trailer", "transfer-encoding", "upgrade", "x-internal-token", "close"] {
+        assert!(names.contains(name), "{} should be treated as hop-by-hop", name);
+    }
+    assert!(!names.contains("content-type"));
+}
+
+#[test]
+fn test_x_forwarded_for_appends_client() {
+    let client: IpAddr = "10.0.0.7".parse().unwrap();
+
+    assert_eq!(x_forwarded_for(None, client), "10.0.0.7");
+    assert_eq!(x_forwarded_for(Some("203.0.113.1"), client), "203.0.113.1, 10.0.0.7");
+}
+
+#[test]
+fn test_forwarded_element() {
+    let v4: IpAddr = "192.0.2.60".parse().unwrap();
+    let v6: IpAddr = "2001:db8::1".parse().unwrap();
+
+    assert_eq!(
+        forwarded(None, v4, Some("example.com"), "https"),
+        "for=192.0.2.60;host=\"example.com\";proto=https"
+    );
+    assert_eq!(
+        forwarded(Some("for=198.51.100.17"), v6, None, "http"),
+        "for=198.51.100.17, for=\"[2001:db8::1]\";proto=http"
+    );
+}
+
+#[test]
+fn test_parse_forwarded_headers_mode() {
+    let both: ForwardedHeaders = "both".parse().unwrap();
+    assert!(both.x_forwarded() && both.forwarded());
+
+    let none: ForwardedHeaders = "none".parse().unwrap();
+    assert!(!none.x_forwarded() && !none.forwarded());
+
+    assert!("x-forwarded".parse::<ForwardedHeaders>().is_err());
+}
+
+#[test]
+fn test_identity_headers_replace_api_key() {
+    let api_key = ApiKey {
+        id: 7,
+        user_id: Some(3),
+        product_id: None,
+    };
+
+    assert_eq!(
+        identity_headers(&api_key),
+        vec![("x-consumer-id", "7".to_string()), ("x-user-id", "3".to_string())]
+    );
+    for (name, _) in identity_headers(&api_key) {
+        assert!(CLIENT_ONLY_HEADERS.contains(&name), "clients must not be able to spoof {}", name);
+    }
+    assert!(CLIENT_ONLY_HEADERS.contains(&"x-api-key"));
+}
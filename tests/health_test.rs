@@ -0,0 +1,83 @@
+use reverse_proxy::config::{EndpointConfig, HealthCheckConfig, PassiveHealthCheckConfig, UpstreamConfig};
+use reverse_proxy::health::run_health_checks;
+use reverse_proxy::upstream::Upstream;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn endpoints(urls: &[&str]) -> Vec<EndpointConfig> {
+    urls.iter().map(|url| EndpointConfig { url: url.to_string(), weight: 1 }).collect()
+}
+
+#[test]
+fn test_passive_ejection_and_reinstatement() {
+    let config = UpstreamConfig {
+        endpoints: endpoints(&["http://a", "http://b"]),
+        passive_health_check: Some(PassiveHealthCheckConfig { max_failures: 2, ejection_seconds: 0 }),
+        ..Default::default()
+    };
+    let upstream = Upstream::new("backend", &config).unwrap();
+    let a = &upstream.endpoints()[0];
+
+    a.record_failure();
+    a.record_success();
+    a.record_failure();
+    assert!(a.is_available(), "failures must be consecutive");
+
+    a.record_failure();
+    // Zero-second ejections expire immediately
+    assert!(a.is_available());
+
+    let config = UpstreamConfig {
+        passive_health_check: Some(PassiveHealthCheckConfig { max_failures: 1, ejection_seconds: 60 }),
+        ..config
+    };
+    let upstream = Upstream::new("backend", &config).unwrap();
+    upstream.endpoints()[0].record_failure();
+    for _ in 0..4 {
+        assert_eq!(upstream.select(None).unwrap().url, "http://b");
+    }
+
+    upstream.endpoints()[1].record_failure();
+    assert!(upstream.select(None).is_err());
+}
+
+#[test]
+fn test_failures_ignored_without_passive_checks() {
+    let config = UpstreamConfig {
+        url: Some("http://a".to_string()),
+        ..Default::default()
+    };
+    let upstream = Upstream::new("backend", &config).unwrap();
+    for _ in 0..10 {
+        upstream.endpoints()[0].record_failure();
+    }
+    assert!(upstream.select(None).is_ok());
+}
+
+#[actix_rt::test]
+async fn test_active_checks_take_down_unreachable_endpoints() {
+    let health_check = HealthCheckConfig {
+        interval_seconds: 1,
+        timeout_seconds: 1,
+        unhealthy_threshold: 1,
+        ..Default::default()
+    };
+    let config = UpstreamConfig {
+        url: Some("http://127.0.0.1:1".to_string()),
+        health_check: Some(health_check.clone()),
+        ..Default::default()
+    };
+    let upstream = Arc::new(Upstream::new("backend", &config).unwrap());
+    assert!(upstream.select(None).is_ok());
+
+    let checks = actix_rt::spawn(run_health_checks(upstream.clone(), health_check));
+    for _ in 0..20 {
+        if upstream.select(None).is_err() {
+            break;
+        }
+        actix_rt::time::sleep(Duration::from_millis(100)).await;
+    }
+    checks.abort();
+
+    assert!(upstream.select(None).is_err());
+}
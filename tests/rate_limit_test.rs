@@ -0,0 +1,158 @@
+use actix_web::http::header::HeaderMap;
+use reverse_proxy::{LocalLimiter, RateLimitAlgorithm, RateLimitDecision, RateLimitKey, RateLimitSubject, RedisFailurePolicy};
+use std::time::Duration;
+
+fn decision(allowed: bool, remaining: u32, reset_ms: u64) -> RateLimitDecision {
+    RateLimitDecision {
+        allowed,
+        limit: 10,
+        remaining,
+        reset_after: Duration::from_millis(reset_ms),
+    }
+}
+
+#[test]
+fn test_parse_rate_limit_algorithm() {
+    assert_eq!("fixed_window".parse::<RateLimitAlgorithm>(), Ok(RateLimitAlgorithm::FixedWindow));
+    assert_eq!("sliding_log".parse::<RateLimitAlgorithm>(), Ok(RateLimitAlgorithm::SlidingLog));
+    assert_eq!("sliding_window".parse::<RateLimitAlgorithm>(), Ok(RateLimitAlgorithm::SlidingWindow));
+    assert_eq!("token_bucket".parse::<RateLimitAlgorithm>(), Ok(RateLimitAlgorithm::TokenBucket));
+    assert!("leaky_bucket".parse::<RateLimitAlgorithm>().is_err());
+}
+
+#[test]
+fn test_allowed_decision_headers() {
+    let mut headers = HeaderMap::new();
+    decision(true, 7, 1500).apply_headers(&mut headers);
+
+    assert_eq!(headers.get("ratelimit-limit").unwrap(), "10");
+    assert_eq!(headers.get("ratelimit-remaining").unwrap(), "7");
+    assert_eq!(headers.get("ratelimit-reset").unwrap(), "2");
+    assert!(headers.get("retry-after").is_none());
+}
+
+#[test]
+fn test_rejected_decision_sets_retry_after() {
+    let mut headers = HeaderMap::new();
+    decision(false, 0, 0).apply_headers(&mut headers);
+
+    assert_eq!(headers.get("ratelimit-remaining").unwrap(), "0");
+    assert_eq!(headers.get("retry-after").unwrap(), "1");
+}
+
+#[test]
+fn test_most_restrictive_decision() {
+    let tighter = decision(true, 1, 1000).most_restrictive(decision(true, 5, 1000));
+    assert_eq!(tighter.remaining, 1);
+
+    let rejected = decision(true, 1, 1000).most_restrictive(decision(false, 0, 30_000));
+    assert!(!rejected.allowed);
+    assert_eq!(rejected.reset_after, Duration::from_secs(30));
+}
+
+#[test]
+fn test_rate_limit_key_ids() {
+    let subject = RateLimitSubject {
+        api_key_id: 42,
+        ip: "10.0.0.1",
+        route: "/api/v1/orders",
+    };
+
+    assert_eq!(RateLimitKey::Ip.id(&subject), "ip:10.0.0.1");
+    assert_eq!(RateLimitKey::ApiKey.id(&subject), "key:42");
+    assert_eq!(RateLimitKey::ApiKeyIp.id(&subject), "key_ip:42:10.0.0.1");
+    assert_eq!(RateLimitKey::ApiKeyRoute.id(&subject), "key_route:42:/api/v1/orders");
+    assert_eq!("api_key_route".parse::<RateLimitKey>(), Ok(RateLimitKey::ApiKeyRoute));
+}
+
+#[test]
+fn test_local_limiter_enforces_limit_per_key() {
+    let limiter = LocalLimiter::new();
+    let window = Duration::from_secs(60);
+
+    assert!(limiter.check("http:a", 2, window).allowed);
+    let second = limiter.check("http:a", 2, window);
+    assert!(second.allowed);
+    assert_eq!(second.remaining, 0);
+    assert!(!limiter.check("http:a", 2, window).allowed);
+    assert!(limiter.check("http:b", 2, window).allowed);
+}
+
+#[test]
+fn test_local_limiter_resets_after_window() {
+    let limiter = LocalLimiter::new();
+    let window = Duration::from_millis(20);
+
+    assert!(limiter.check("ws:a", 1, window).allowed);
+    assert!(!limiter.check("ws:a", 1, window).allowed);
+    std::thread::sleep(Duration::from_millis(30));
+    assert!(limiter.check("ws:a", 1, window).allowed);
+}
+
+#[test]
+fn test_local_limiter_counts_all_or_nothing() {
+    let limiter = LocalLimiter::new();
+    let minute = Duration::from_secs(60);
+    let checks = [("quota:http", 5, minute), ("quota:route", 1, minute)];
+    assert!(limiter.check_all(&checks).iter().all(|d| d.allowed));
+
+    // The exhausted route quota rejects without charging the feature quota
+    for _ in 0..3 {
+        let decisions = limiter.check_all(&checks);
+        assert_eq!(decisions.iter().map(|d| d.allowed).collect::<Vec<_>>(), [true, false]);
+        assert_eq!(decisions[0].remaining, 4);
+    }
+    assert_eq!(limiter.check("quota:http", 5, minute).remaining, 3);
+}
+
+#[test]
+fn test_local_limiter_eviction_keeps_long_windows() {
+    let limiter = LocalLimiter::new();
+    let day = Duration::from_secs(86_400);
+    assert!(limiter.check("quota:day", 1, day).allowed);
+
+    // Fill the map with short windows that have all ended
+    for i in 0..100_000 {
+        limiter.check(&format!("http:{}", i), 10, Duration::from_millis(1));
+    }
+    std::thread::sleep(Duration::from_millis(5));
+
+    // The sweep triggered by a per-second key removes only ended windows
+    assert!(limiter.check("http:new", 10, Duration::from_secs(1)).allowed);
+    assert!(!limiter.check("quota:day", 1, day).allowed);
+
+    // A map full of live windows loses its oldest ones, once
+    let limiter = LocalLimiter::new();
+    for i in 0..100_000 {
+        limiter.check(&format!("quota:{}", i), 1, day);
+    }
+    assert!(limiter.check("quota:new", 1, day).allowed);
+    assert!(limiter.check("quota:0", 1, day).allowed);
+    assert!(!limiter.check("quota:99999", 1, day).allowed);
+}
+
+#[test]
+fn test_local_limiter_eviction_at_target_size() {
+    // Ended windows make up exactly the room eviction aims to free
+    let limiter = LocalLimiter::new();
+    let day = Duration::from_secs(86_400);
+    for i in 0..90_000 {
+        limiter.check(&format!("quota:{}", i), 1, day);
+    }
+    for i in 0..10_000 {
+        limiter.check(&format!("http:{}", i), 10, Duration::from_millis(1));
+    }
+    std::thread::sleep(Duration::from_millis(5));
+
+    // The sweep alone is enough, so every live window is kept
+    assert!(limiter.check("quota:new", 1, day).allowed);
+    assert!(!limiter.check("quota:0", 1, day).allowed);
+    assert!(!limiter.check("quota:89999", 1, day).allowed);
+}
+
+#[test]
+fn test_parse_redis_failure_policy() {
+    assert_eq!("fail_open".parse::<RedisFailurePolicy>(), Ok(RedisFailurePolicy::FailOpen));
+    assert_eq!("fail_closed".parse::<RedisFailurePolicy>(), Ok(RedisFailurePolicy::FailClosed));
+    assert_eq!("local".parse::<RedisFailurePolicy>(), Ok(RedisFailurePolicy::LocalFallback));
+}
@@ -0,0 +1,62 @@
+use reverse_proxy::config::RetryConfig;
+use reverse_proxy::retry::{is_idempotent, RetryPolicy};
+use std::time::Duration;
+
+#[test]
+fn test_idempotent_methods() {
+    for method in ["GET", "HEAD", "OPTIONS", "PUT", "DELETE"] {
+        assert!(is_idempotent(method), "{} is idempotent", method);
+    }
+    assert!(!is_idempotent("POST"));
+    assert!(!is_idempotent("PATCH"));
+}
+
+#[test]
+fn test_backoff_is_capped_and_jittered() {
+    let policy = RetryPolicy::new(RetryConfig {
+        backoff_ms: 100,
+        max_backoff_ms: 300,
+        ..Default::default()
+    });
+
+    for _ in 0..50 {
+        assert!(policy.backoff(1) <= Duration::from_millis(100));
+        assert!(policy.backoff(2) <= Duration::from_millis(200));
+        assert!(policy.backoff(10) <= Duration::from_millis(300));
+    }
+    let waits: std::collections::HashSet<Duration> = (0..50).map(|_| policy.backoff(3)).collect();
+    assert!(waits.len() > 1, "backoff should be jittered");
+
+    // Waits spread over the whole range; without a base backoff there is none
+    let waits: Vec<u64> = (0..2_000).map(|_| policy.backoff(1).as_millis() as u64).collect();
+    for quarter in 0..4 {
+        assert!(waits.iter().any(|&w| w / 25 == quarter), "no wait in quarter {}", quarter);
+    }
+    let policy = RetryPolicy::new(RetryConfig { backoff_ms: 0, ..Default::default() });
+    assert_eq!(policy.backoff(3), Duration::ZERO);
+}
+
+#[test]
+fn test_retry_budget() {
+    let policy = RetryPolicy::new(RetryConfig {
+        budget_ratio: 0.5,
+        min_retries_per_second: 0,
+        ..Default::default()
+    });
+    assert!(!policy.try_retry(), "no traffic, no budget");
+
+    for _ in 0..4 {
+        policy.record_request();
+    }
+    assert!(policy.try_retry());
+    assert!(policy.try_retry());
+    assert!(!policy.try_retry());
+}
+
+#[test]
+fn test_retried_statuses() {
+    let policy = RetryPolicy::new(RetryConfig::default());
+    assert!(policy.retries_status(503));
+    assert!(!policy.retries_status(500));
+    assert!(!policy.retries_status(404));
+}
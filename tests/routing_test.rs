@@ -0,0 +1,117 @@
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use reverse_proxy::config::ProxyConfig;
+use reverse_proxy::handlers::headers::ForwardedHeaders;
+use reverse_proxy::routing::{RouteKind, RouteRequest, RouteTable};
+
+const CONFIG: &str = r#"
+[upstreams.orders]
+url = "http://orders:8080/"
+
+[upstreams.feed]
+url = "ws://feed:9000"
+
+[[routes]]
+name = "feed"
+upstream = "feed"
+kind = "websocket"
+path_prefix = "/ws"
+methods = ["get"]
+
+[[routes]]
+name = "tenant"
+upstream = "orders"
+host = "*.example.com"
+path_regex = "^/api/v1/orders/(.*)$"
+rewrite = "/v2/orders/$1"
+headers = { "X-Tenant" = "acme" }
+forwarded_headers = "none"
+
+[[routes]]
+name = "secure"
+upstream = "orders"
+scheme = "https"
+strip_prefix = "/api/v1"
+add_prefix = "/internal"
+"#;
+
+fn table() -> RouteTable {
+    let config = ProxyConfig::from_toml(CONFIG).unwrap();
+    RouteTable::new(&config, ForwardedHeaders::Both).unwrap()
+}
+
+fn request<'a>(method: &'a str, scheme: &'a str, host: &'a str, path: &'a str, headers: &'a HeaderMap) -> RouteRequest<'a, HeaderMap> {
+    RouteRequest { method, scheme, host: Some(host), path, headers }
+}
+
+#[test]
+fn test_first_matching_route_wins() {
+    let table = table();
+    let mut tenant = HeaderMap::new();
+    tenant.insert(HeaderName::from_static("x-tenant"), HeaderValue::from_static("acme"));
+    let none = HeaderMap::new();
+
+    let route = table.find(&request("GET", "http", "proxy:8080", "/ws/prices", &none)).unwrap();
+    assert_eq!(route.name, "feed");
+    assert_eq!(route.kind, RouteKind::Websocket);
+
+    let route = table.find(&request("POST", "https", "shop.example.com:443", "/api/v1/orders/7", &tenant)).unwrap();
+    assert_eq!(route.name, "tenant");
+    assert_eq!(route.forwarded_headers, ForwardedHeaders::None);
+
+    // Without the tenant header the next route applies
+    let route = table.find(&request("POST", "https", "shop.example.com", "/api/v1/orders/7", &none)).unwrap();
+    assert_eq!(route.name, "secure");
+    assert_eq!(route.forwarded_headers, ForwardedHeaders::Both);
+}
+
+#[test]
+fn test_unmatched_requests() {
+    let table = table();
+    let headers = HeaderMap::new();
+
+    // Prefixes match whole segments only, and methods are checked
+    assert!(table.find(&request("GET", "http", "proxy", "/wsx", &headers)).is_none());
+    assert!(table.find(&request("POST", "http", "proxy", "/ws", &headers)).is_none());
+    assert!(table.find(&request("GET", "http", "example.com", "/api/v1/orders/7", &headers)).is_none());
+}
+
+#[test]
+fn test_upstream_url() {
+    let table = table();
+    let mut tenant = HeaderMap::new();
+    tenant.insert(HeaderName::from_static("x-tenant"), HeaderValue::from_static("acme"));
+
+    let route = table.find(&request("GET", "https", "a.example.com", "/api/v1/orders/7", &tenant)).unwrap();
+    assert_eq!(route.upstream_url(&route.upstream.select(None).unwrap(), "/api/v1/orders/7", Some("expand=items")), "http://orders:8080/v2/orders/7?expand=items");
+
+    let route = table.find(&request("GET", "https", "proxy", "/api/v1/users", &tenant)).unwrap();
+    assert_eq!(route.upstream_url(&route.upstream.select(None).unwrap(), "/api/v1/users", None), "http://orders:8080/internal/users");
+
+    let route = table.find(&request("GET", "http", "proxy", "/ws", &tenant)).unwrap();
+    assert_eq!(route.upstream_url(&route.upstream.select(None).unwrap(), "/ws", Some("")), "ws://feed:9000/ws");
+}
+
+#[test]
+fn test_invalid_config() {
+    let unknown_upstream = r#"
+[[routes]]
+name = "orphan"
+upstream = "missing"
+"#;
+    let config = ProxyConfig::from_toml(unknown_upstream).unwrap();
+    assert!(RouteTable::new(&config, ForwardedHeaders::Both).is_err());
+
+    let rewrite_without_regex = r#"
+[upstreams.orders]
+url = "http://orders"
+
+[[routes]]
+name = "orders"
+upstream = "orders"
+rewrite = "/v2"
+"#;
+    let config = ProxyConfig::from_toml(rewrite_without_regex).unwrap();
+    assert!(RouteTable::new(&config, ForwardedHeaders::Both).is_err());
+
+    assert!(ProxyConfig::from_toml("[[routes]]\nname = \"x\"\nupstream = \"y\"\nunknown = 1\n").is_err());
+}
@@ -0,0 +1,140 @@
+use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+use futures::StreamExt;
+use reverse_proxy::config::ProxyConfig;
+use reverse_proxy::handlers::headers::ForwardedHeaders;
+use reverse_proxy::handlers::regular::forward_request;
+use reverse_proxy::handlers::sse::{accepts_event_stream, is_event_stream, with_idle_timeout, StreamLimiter};
+use reverse_proxy::routing::{RouteRequest, RouteTable};
+use std::net::TcpListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Requests the upstream has been sent.
+static UPSTREAM_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Sends one event, then keeps the stream open without sending anything.
+/// Records the `Accept-Encoding` it was sent in an `x-accept-encoding` header.
+async fn events(req: HttpRequest) -> HttpResponse {
+    UPSTREAM_REQUESTS.fetch_add(1, Ordering::SeqCst);
+    let accept_encoding = req.headers().get("accept-encoding").map(|v| v.to_str().unwrap().to_string());
+    let first = futures::stream::once(async { Ok::<_, actix_web::Error>(web::Bytes::from_static(b"data: 1\n\n")) });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("x-accept-encoding", accept_encoding.unwrap_or_default()))
+        .streaming(first.chain(futures::stream::pending()))
+}
+
+fn listener() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    (listener, url)
+}
+
+#[actix_rt::test]
+async fn test_event_streams_through_proxy() {
+    let (upstream_listener, upstream_url) = listener();
+    let upstream = HttpServer::new(|| App::new().route("/events", web::get().to(events)))
+        .workers(1)
+        .listen(upstream_listener)
+        .unwrap()
+        .run();
+    let upstream_handle = upstream.handle();
+    actix_rt::spawn(upstream);
+    let config = ProxyConfig::from_toml(&format!(
+        "[upstreams.events]\nurl = \"{}\"\n[[routes]]\nname = \"events\"\nupstream = \"events\"\nsse = {{ idle_timeout_ms = 300, max_streams_per_key = 1 }}\n",
+        upstream_url
+    ))
+    .unwrap();
+    let headers = actix_web::http::header::HeaderMap::new();
+    let request = RouteRequest { method: "GET", scheme: "http", host: None, path: "/events", headers: &headers };
+    let route = RouteTable::new(&config, ForwardedHeaders::None).unwrap().find(&request).unwrap();
+    let (proxy_listener, proxy_url) = listener();
+    let proxy = HttpServer::new(move || {
+        let route = Arc::clone(&route);
+        App::new().default_service(web::to(move |req, payload| forward_request(req, payload, Arc::clone(&route))))
+    })
+    .workers(1)
+    .listen(proxy_listener)
+    .unwrap()
+    .run();
+    let proxy_handle = proxy.handle();
+    actix_rt::spawn(proxy);
+    let client = reqwest::Client::new();
+    let get = || {
+        client
+            .get(format!("{}/events", proxy_url))
+            .header("accept", "text/event-stream")
+            .header("accept-encoding", "gzip")
+            .send()
+    };
+
+    let first = get().await.unwrap();
+    assert_eq!(first.headers()["content-type"], "text/event-stream");
+    assert_eq!(first.headers()["cache-control"], "no-cache");
+    assert_eq!(first.headers()["x-accel-buffering"], "no");
+    assert_eq!(first.headers()["x-accept-encoding"], "identity");
+
+    // The event arrives before the stream ends
+    let mut body = first.bytes_stream();
+    assert_eq!(&body.next().await.unwrap().unwrap()[..], b"data: 1\n\n");
+
+    // One stream per key while the first is open, refused before reaching
+    // the upstream when the client asks for an event stream
+    assert_eq!(get().await.unwrap().status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(UPSTREAM_REQUESTS.load(Ordering::SeqCst), 1);
+    let unannounced = client.get(format!("{}/events", proxy_url)).send().await.unwrap();
+    assert_eq!(unannounced.status(), reqwest::StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(UPSTREAM_REQUESTS.load(Ordering::SeqCst), 2);
+
+    // The idle stream is closed, freeing its slot
+    let end = tokio::time::timeout(Duration::from_secs(5), body.next()).await.unwrap();
+    assert!(end.is_none());
+    assert_eq!(get().await.unwrap().status(), reqwest::StatusCode::OK);
+
+    proxy_handle.stop(false).await;
+    upstream_handle.stop(false).await;
+}
+
+#[test]
+fn test_stream_limiter() {
+    let limiter = Arc::new(StreamLimiter::new(2));
+    let first = limiter.acquire(Some(1)).unwrap();
+    let _second = limiter.acquire(Some(1)).unwrap();
+    assert!(limiter.acquire(Some(1)).is_none());
+    assert!(limiter.acquire(Some(2)).is_some());
+
+    drop(first);
+    assert_eq!(limiter.open(Some(1)), 1);
+    assert!(limiter.acquire(Some(1)).is_some());
+
+    // 0 means no limit
+    let unlimited = Arc::new(StreamLimiter::new(0));
+    let guards: Vec<_> = (0..100).map(|_| unlimited.acquire(None).unwrap()).collect();
+    assert_eq!(unlimited.open(None), 100);
+    drop(guards);
+    assert_eq!(unlimited.open(None), 0);
+}
+
+#[actix_rt::test]
+async fn test_idle_timeout_ends_stream() {
+    let stream = futures::stream::iter([1, 2]).chain(futures::stream::pending());
+    let items: Vec<_> = with_idle_timeout(stream, Duration::from_millis(50)).collect().await;
+    assert_eq!(items, [1, 2]);
+}
+
+#[test]
+fn test_event_stream_detection() {
+    let mut headers = reqwest::header::HeaderMap::new();
+    assert!(!is_event_stream(&headers));
+    headers.insert("content-type", "text/event-stream; charset=utf-8".parse().unwrap());
+    assert!(is_event_stream(&headers));
+
+    assert!(accepts_event_stream(["application/json, text/event-stream"]));
+    assert!(!accepts_event_stream(["*/*"]));
+
+    let config = ProxyConfig::from_toml("[upstreams.a]\nurl = \"http://a\"\n[[routes]]\nname = \"a\"\nupstream = \"a\"\n").unwrap();
+    assert_eq!(config.routes[0].sse.idle_timeout_ms, 300_000);
+    assert_eq!(config.routes[0].sse.max_streams_per_key, 0);
+    assert!(ProxyConfig::from_toml("[upstreams.a]\nurl = \"http://a\"\n[[routes]]\nname = \"a\"\nupstream = \"a\"\nsse = { idle = 1 }\n").is_err());
+}
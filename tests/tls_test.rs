@@ -0,0 +1,364 @@
+use reverse_proxy::config::{
+    CertificateConfig, ClientAuthConfig, ConfigError, ProxyConfig, EndpointConfig, TlsConfig, TlsVersion, UpstreamConfig, UpstreamTlsConfig,
+};
+use reverse_proxy::db::{ApiKey, CertificateBinding};
+use reverse_proxy::errors::ProxyError;
+use reverse_proxy::keys::KeyStore;
+use reverse_proxy::tls::{server_config, upstream_client_config, CertificateResolver, ClientCertificate};
+use reverse_proxy::upstream::Upstream;
+use rustls::crypto::ring::default_provider;
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::version::{TLS12, TLS13};
+use rustls::{ClientConfig, ClientConnection, ProtocolVersion, RootCertStore, ServerConnection, SupportedProtocolVersion};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+fn files(name: &str) -> CertificateConfig {
+    CertificateConfig { cert: fixture(&format!("{}.pem", name)), key: fixture(&format!("{}.key", name)) }
+}
+
+fn leaf(path: &str) -> CertificateDer<'static> {
+    rustls_pemfile::certs(&mut BufReader::new(File::open(path).unwrap())).next().unwrap().unwrap()
+}
+
+fn tls_config(min_version: TlsVersion) -> TlsConfig {
+    TlsConfig {
+        port: 8443,
+        min_version,
+        reload_seconds: 30,
+        certificates: vec![files("a"), files("b")],
+        client_auth: None,
+    }
+}
+
+const CLIENT_FINGERPRINT: &str = "70e8b0b23a9efcb8d88d2a8a3822f27d90cfe628f851bb88eef439acc85f8e43";
+const CLIENT_SUBJECT: &str = "CN=client.example.test,O=Acme";
+/// SHA-256 of the public keys of a.pem and b.pem.
+const A_PIN: &str = "4363745c402f7926ee8677cf88092b124288a481c0456c93172ba2f6a53b38b1";
+const B_PIN: &str = "0c3d2d8705777f7bef631c734618d1ac5e423a0f8d20cf671822d331274a3a6e";
+
+struct Handshake {
+    certificate: CertificateDer<'static>,
+    version: ProtocolVersion,
+    client_certificate: Option<ClientCertificate>,
+}
+
+/// Runs a handshake in memory, optionally presenting `client` (a fixture
+/// name) as the client certificate.
+fn handshake(
+    config: &TlsConfig,
+    server_name: &str,
+    client_versions: &[&'static SupportedProtocolVersion],
+    client: Option<&str>,
+) -> Result<Handshake, rustls::Error> {
+    let mut roots = RootCertStore::empty();
+    roots.add(leaf(&fixture("ca.pem"))).unwrap();
+    let builder = ClientConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_protocol_versions(client_versions)
+        .unwrap()
+        .with_root_certificates(roots);
+    let client_config = match client {
+        Some(name) => {
+            let chain = vec![leaf(&fixture(&format!("{}.pem", name)))];
+            let mut key_file = BufReader::new(File::open(fixture(&format!("{}.key", name))).unwrap());
+            let key = rustls_pemfile::private_key(&mut key_file).unwrap().unwrap();
+            builder.with_client_auth_cert(chain, key).unwrap()
+        }
+        None => builder.with_no_client_auth(),
+    };
+    connect(config, client_config, server_name)
+}
+
+/// Runs a handshake in memory between the proxy's TLS listener and a client.
+fn connect(config: &TlsConfig, client_config: ClientConfig, server_name: &str) -> Result<Handshake, rustls::Error> {
+    let resolver = Arc::new(CertificateResolver::new(config.certificates.clone()).unwrap());
+    let mut server = ServerConnection::new(Arc::new(server_config(config, resolver).unwrap())).unwrap();
+    let server_name = ServerName::try_from(server_name.to_string()).unwrap();
+    let mut client = ClientConnection::new(Arc::new(client_config), server_name).unwrap();
+
+    let mut buffer = Vec::new();
+    while client.is_handshaking() || server.is_handshaking() {
+        buffer.clear();
+        client.write_tls(&mut buffer).unwrap();
+        server.read_tls(&mut buffer.as_slice()).unwrap();
+        server.process_new_packets()?;
+
+        buffer.clear();
+        server.write_tls(&mut buffer).unwrap();
+        client.read_tls(&mut buffer.as_slice()).unwrap();
+        client.process_new_packets()?;
+    }
+
+    Ok(Handshake {
+        certificate: client.peer_certificates().unwrap()[0].clone().into_owned(),
+        version: client.protocol_version().unwrap(),
+        client_certificate: server
+            .peer_certificates()
+            .and_then(|chain| chain.first())
+            .and_then(|der| ClientCertificate::from_der(der)),
+    })
+}
+
+#[test]
+fn test_certificate_chosen_by_server_name() {
+    let resolver = CertificateResolver::new(vec![files("a"), files("b")]).unwrap();
+    let chosen = |name: Option<&str>| resolver.certificate_for(name).unwrap().cert[0].clone();
+
+    assert_eq!(chosen(Some("a.example.test")), leaf(&fixture("a.pem")));
+    assert_eq!(chosen(Some("api.b.example.test")), leaf(&fixture("b.pem")));
+    // Wildcards cover a single label
+    assert_eq!(chosen(Some("b.example.test")), leaf(&fixture("a.pem")));
+    // Unknown names and clients without SNI get the first certificate
+    assert_eq!(chosen(Some("other.test")), leaf(&fixture("a.pem")));
+    assert_eq!(chosen(None), leaf(&fixture("a.pem")));
+}
+
+#[test]
+fn test_handshake_serves_certificate_for_sni() {
+    let config = tls_config(TlsVersion::Tls12);
+
+    let result = handshake(&config, "a.example.test", &[&TLS13, &TLS12], None).unwrap();
+    assert_eq!(result.certificate, leaf(&fixture("a.pem")));
+    assert_eq!(result.version, ProtocolVersion::TLSv1_3);
+
+    let result = handshake(&config, "api.b.example.test", &[&TLS13, &TLS12], None).unwrap();
+    assert_eq!(result.certificate, leaf(&fixture("b.pem")));
+}
+
+#[test]
+fn test_min_version() {
+    let result = handshake(&tls_config(TlsVersion::Tls12), "a.example.test", &[&TLS12], None).unwrap();
+    assert_eq!(result.version, ProtocolVersion::TLSv1_2);
+
+    assert!(handshake(&tls_config(TlsVersion::Tls13), "a.example.test", &[&TLS12], None).is_err());
+    let result = handshake(&tls_config(TlsVersion::Tls13), "a.example.test", &[&TLS13], None).unwrap();
+    assert_eq!(result.version, ProtocolVersion::TLSv1_3);
+}
+
+#[test]
+fn test_invalid_certificates() {
+    let mismatched = CertificateConfig { cert: fixture("a.pem"), key: fixture("b.key") };
+    assert!(matches!(CertificateResolver::new(vec![mismatched]), Err(ConfigError::Invalid(_))));
+
+    let missing = CertificateConfig { cert: fixture("missing.pem"), key: fixture("a.key") };
+    assert!(matches!(CertificateResolver::new(vec![missing]), Err(ConfigError::File(..))));
+
+    let swapped = CertificateConfig { cert: fixture("a.key"), key: fixture("a.pem") };
+    assert!(matches!(CertificateResolver::new(vec![swapped]), Err(ConfigError::File(..))));
+
+    assert!(CertificateResolver::new(Vec::new()).is_err());
+}
+
+#[test]
+fn test_tls_config() {
+    let config = ProxyConfig::from_toml(
+        r#"
+        [tls]
+        port = 8443
+        min_version = "1.3"
+
+        [[tls.certificates]]
+        cert = "example.pem"
+        key = "example.key"
+        "#,
+    )
+    .unwrap();
+    let tls = config.tls.unwrap();
+    assert_eq!(tls.min_version, TlsVersion::Tls13);
+    assert_eq!(tls.reload_seconds, 30);
+    assert_eq!(tls.certificates[0].key, "example.key");
+
+    assert!(ProxyConfig::from_toml("[tls]\nport = 8443\nmin_version = \"1.1\"\ncertificates = []").is_err());
+}
+
+#[test]
+fn test_client_certificate_identity() {
+    let certificate = ClientCertificate::from_der(&leaf(&fixture("client.pem"))).unwrap();
+    assert_eq!(certificate.fingerprint, CLIENT_FINGERPRINT);
+    assert_eq!(certificate.subject, CLIENT_SUBJECT);
+}
+
+#[test]
+fn test_client_auth() {
+    let client_auth = |required| ClientAuthConfig { ca: fixture("clients-ca.pem"), required };
+    let optional = TlsConfig { client_auth: Some(client_auth(false)), ..tls_config(TlsVersion::Tls12) };
+    let required = TlsConfig { client_auth: Some(client_auth(true)), ..tls_config(TlsVersion::Tls12) };
+    let versions: &[&'static SupportedProtocolVersion] = &[&TLS13, &TLS12];
+
+    let result = handshake(&optional, "a.example.test", versions, Some("client")).unwrap();
+    assert_eq!(result.client_certificate.unwrap().subject, CLIENT_SUBJECT);
+    let result = handshake(&optional, "a.example.test", versions, None).unwrap();
+    assert!(result.client_certificate.is_none());
+
+    assert!(handshake(&required, "a.example.test", versions, Some("client")).is_ok());
+    assert!(handshake(&required, "a.example.test", versions, None).is_err());
+    // Certificates from other CAs are refused
+    assert!(handshake(&optional, "a.example.test", versions, Some("a")).is_err());
+}
+
+#[test]
+fn test_authenticate_with_client_certificate() {
+    let api_key = |id| ApiKey { id, user_id: Some(id), product_id: None };
+    let api_keys = HashMap::from([("key-1".to_string(), api_key(1)), ("key-2".to_string(), api_key(2))]);
+    let binding = |fingerprint: Option<&str>, subject: Option<&str>, id, require_api_key| CertificateBinding {
+        fingerprint: fingerprint.map(str::to_string),
+        subject: subject.map(str::to_string),
+        api_key: api_key(id),
+        require_api_key,
+    };
+    let client = ClientCertificate { fingerprint: CLIENT_FINGERPRINT.to_string(), subject: CLIENT_SUBJECT.to_string() };
+    let other = ClientCertificate { fingerprint: "00".repeat(32), subject: "CN=other".to_string() };
+
+    // Fingerprints are matched without colons, in any case
+    let colons = "70:E8:B0:B2:3A:9E:FC:B8:D8:8D:2A:8A:38:22:F2:7D:90:CF:E6:28:F8:51:BB:88:EE:F4:39:AC:C8:5F:8E:43";
+    let keys = KeyStore::new(api_keys.clone(), HashMap::new(), vec![binding(Some(colons), None, 1, false)]);
+    assert_eq!(keys.authenticate(None, Some(&client)).unwrap().id, 1);
+    assert_eq!(keys.authenticate(Some("key-1"), Some(&client)).unwrap().id, 1);
+    assert_eq!(keys.authenticate(Some("key-2"), Some(&client)).unwrap_err(), ProxyError::InvalidClientCertificate);
+    assert_eq!(keys.authenticate(None, Some(&other)).unwrap_err(), ProxyError::InvalidClientCertificate);
+    assert_eq!(keys.authenticate(Some("key-2"), None).unwrap().id, 2);
+    assert_eq!(keys.authenticate(Some("key-3"), Some(&client)).unwrap_err(), ProxyError::InvalidApiKey);
+    assert_eq!(keys.authenticate(None, None).unwrap_err(), ProxyError::MissingApiKey);
+
+    let keys = KeyStore::new(api_keys, HashMap::new(), vec![binding(None, Some(CLIENT_SUBJECT), 2, true)]);
+    assert_eq!(keys.authenticate(None, Some(&client)).unwrap_err(), ProxyError::MissingApiKey);
+    assert_eq!(keys.authenticate(Some("key-2"), Some(&client)).unwrap().id, 2);
+}
+
+#[actix_rt::test]
+async fn test_certificates_reloaded_on_change() {
+    let dir = std::env::temp_dir().join(format!("reverse_proxy_tls_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| -> PathBuf { dir.join(name) };
+    fs::copy(fixture("a.pem"), path("cert.pem")).unwrap();
+    fs::copy(fixture("a.key"), path("key.pem")).unwrap();
+
+    let files = CertificateConfig {
+        cert: path("cert.pem").to_string_lossy().into_owned(),
+        key: path("key.pem").to_string_lossy().into_owned(),
+    };
+    let resolver = Arc::new(CertificateResolver::new(vec![files]).unwrap());
+    actix_rt::spawn(resolver.clone().watch(Duration::from_millis(50)));
+    let current = || resolver.certificate_for(None).unwrap().cert[0].clone();
+
+    fs::copy(fixture("b.pem"), path("cert.pem")).unwrap();
+    fs::copy(fixture("b.key"), path("key.pem")).unwrap();
+    actix_rt::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(current(), leaf(&fixture("b.pem")));
+
+    // A broken certificate leaves the last good one in use
+    fs::write(path("cert.pem"), "not a certificate").unwrap();
+    actix_rt::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(current(), leaf(&fixture("b.pem")));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_upstream_tls_config() {
+    let config = ProxyConfig::from_toml(
+        r#"
+        [upstreams.backend]
+        url = "https://10.0.0.5:8443"
+
+        [upstreams.backend.tls]
+        ca = "internal-ca.pem"
+        cert = "proxy.pem"
+        key = "proxy.key"
+        server_name = "backend.internal"
+        pins = ["AB:CD"]
+        "#,
+    )
+    .unwrap();
+    let tls = config.upstreams["backend"].tls.clone().unwrap();
+    assert_eq!(tls.ca.as_deref(), Some("internal-ca.pem"));
+    assert_eq!(tls.server_name.as_deref(), Some("backend.internal"));
+    assert_eq!(tls.pins, vec!["AB:CD"]);
+
+    assert!(ProxyConfig::from_toml("[upstreams.backend]\nurl = \"https://a\"\n[upstreams.backend.tls]\nsni = \"a\"").is_err());
+}
+
+#[test]
+fn test_upstream_server_name() {
+    let config = UpstreamConfig {
+        endpoints: vec![
+            EndpointConfig { url: "https://10.0.0.5:8443/".to_string(), weight: 1 },
+            EndpointConfig { url: "wss://10.0.0.6".to_string(), weight: 1 },
+        ],
+        tls: Some(UpstreamTlsConfig { server_name: Some("a.example.test".to_string()), ..Default::default() }),
+        ..Default::default()
+    };
+    let upstream = Upstream::new("backend", &config).unwrap();
+    let endpoints = upstream.endpoints();
+
+    assert_eq!(endpoints[0].url, "https://10.0.0.5:8443");
+    assert_eq!(endpoints[0].request_url, "https://a.example.test:8443");
+    assert_eq!((endpoints[0].host.as_str(), endpoints[0].port), ("10.0.0.5", 8443));
+    assert_eq!(endpoints[1].request_url, "wss://a.example.test");
+    assert_eq!((endpoints[1].host.as_str(), endpoints[1].port), ("10.0.0.6", 443));
+
+    let without = Upstream::new("backend", &UpstreamConfig { tls: None, ..config }).unwrap();
+    assert_eq!(without.endpoints()[0].request_url, "https://10.0.0.5:8443");
+}
+
+#[test]
+fn test_invalid_upstream_tls() {
+    let invalid = |tls: UpstreamTlsConfig| matches!(upstream_client_config(&tls), Err(ConfigError::Invalid(_)));
+    let ca = || Some(fixture("ca.pem"));
+
+    assert!(upstream_client_config(&UpstreamTlsConfig::default()).is_ok());
+    assert!(invalid(UpstreamTlsConfig { ca: ca(), pins: vec!["abcd".to_string()], ..Default::default() }));
+    assert!(invalid(UpstreamTlsConfig { ca: ca(), cert: Some(fixture("client.pem")), ..Default::default() }));
+    assert!(invalid(UpstreamTlsConfig {
+        ca: ca(),
+        cert: Some(fixture("client.pem")),
+        key: Some(fixture("a.key")),
+        ..Default::default()
+    }));
+    assert!(matches!(
+        upstream_client_config(&UpstreamTlsConfig { ca: Some(fixture("missing.pem")), ..Default::default() }),
+        Err(ConfigError::File(..))
+    ));
+    // Bad TLS settings fail the upstream, not the first request
+    let config = UpstreamConfig {
+        url: Some("https://a.example.test".to_string()),
+        tls: Some(UpstreamTlsConfig { pins: vec!["zz".to_string()], ..Default::default() }),
+        ..Default::default()
+    };
+    assert!(Upstream::new("backend", &config).is_err());
+}
+
+#[test]
+fn test_upstream_handshake() {
+    let upstream = |cert: bool, pins: &[&str]| {
+        upstream_client_config(&UpstreamTlsConfig {
+            ca: Some(fixture("ca.pem")),
+            cert: cert.then(|| fixture("client.pem")),
+            key: cert.then(|| fixture("client.key")),
+            server_name: None,
+            pins: pins.iter().map(|pin| pin.to_string()).collect(),
+        })
+        .unwrap()
+    };
+    let client_auth = ClientAuthConfig { ca: fixture("clients-ca.pem"), required: true };
+    let server = TlsConfig { client_auth: Some(client_auth), ..tls_config(TlsVersion::Tls12) };
+
+    let result = connect(&server, upstream(true, &[]), "a.example.test").unwrap();
+    assert_eq!(result.client_certificate.unwrap().subject, CLIENT_SUBJECT);
+    assert!(connect(&server, upstream(false, &[]), "a.example.test").is_err());
+    // The custom CA is still checked against the server name
+    assert!(connect(&server, upstream(true, &[]), "other.test").is_err());
+
+    let server = tls_config(TlsVersion::Tls12);
+    assert!(connect(&server, upstream(false, &[A_PIN]), "a.example.test").is_ok());
+    assert!(connect(&server, upstream(false, &[&A_PIN.to_uppercase(), B_PIN]), "a.example.test").is_ok());
+    assert!(connect(&server, upstream(false, &[B_PIN]), "a.example.test").is_err());
+}
@@ -0,0 +1,281 @@
+use actix_web::{web, App, HttpRequest, HttpServer};
+use reverse_proxy::config::{EndpointConfig, ProxyConfig, UpstreamConfig, UpstreamTlsConfig};
+use reverse_proxy::handlers::headers::ForwardedHeaders;
+use reverse_proxy::handlers::regular::forward_request;
+use reverse_proxy::routing::{RouteRequest, RouteTable};
+use reverse_proxy::upstream::{HttpVersion, LoadBalancing, Upstream};
+use rustls::crypto::ring::default_provider;
+use rustls::server::Acceptor;
+use rustls::ServerConfig;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::net::TcpListener;
+use std::sync::Arc;
+use tokio::io::AsyncReadExt;
+
+fn upstream(strategy: LoadBalancing, weights: &[u32]) -> Upstream {
+    let config = UpstreamConfig {
+        url: None,
+        endpoints: weights
+            .iter()
+            .enumerate()
+            .map(|(i, &weight)| EndpointConfig { url: format!("http://backend-{}/", i), weight })
+            .collect(),
+        strategy,
+        ..Default::default()
+    };
+    Upstream::new("backend", &config).unwrap()
+}
+
+fn picks(upstream: &Upstream, count: usize, key: Option<i32>) -> HashMap<String, usize> {
+    let mut counts = HashMap::new();
+    for _ in 0..count {
+        *counts.entry(upstream.select(key).unwrap().url.clone()).or_insert(0) += 1;
+    }
+    counts
+}
+
+#[test]
+fn test_round_robin_cycles_endpoints() {
+    let upstream = upstream(LoadBalancing::RoundRobin, &[1, 5, 1]);
+    let urls: Vec<String> = (0..4).map(|_| upstream.select(None).unwrap().url.clone()).collect();
+
+    assert_eq!(urls, ["http://backend-0", "http://backend-1", "http://backend-2", "http://backend-0"]);
+}
+
+#[test]
+fn test_weighted_follows_weights() {
+    let upstream = upstream(LoadBalancing::Weighted, &[3, 1]);
+    let counts = picks(&upstream, 400, None);
+
+    assert_eq!(counts["http://backend-0"], 300);
+    assert_eq!(counts["http://backend-1"], 100);
+}
+
+#[test]
+fn test_least_connections_avoids_busy_endpoints() {
+    let upstream = upstream(LoadBalancing::LeastConnections, &[1, 1]);
+    let busy = upstream.select(None).unwrap();
+    let busy_url = busy.url.clone();
+
+    for _ in 0..5 {
+        assert_ne!(upstream.select(None).unwrap().url, busy_url);
+    }
+    assert_eq!(busy.active_connections(), 1);
+
+    drop(busy);
+    assert_eq!(upstream.endpoints().iter().map(|e| e.active_connections()).sum::<usize>(), 0);
+}
+
+#[test]
+fn test_consistent_hash_pins_api_keys() {
+    let upstream = upstream(LoadBalancing::ConsistentHash, &[1, 1, 1]);
+
+    for key in 0..50 {
+        assert_eq!(picks(&upstream, 5, Some(key)).len(), 1, "key {} moved between endpoints", key);
+    }
+    let spread: std::collections::HashSet<String> = (0..50).map(|key| upstream.select(Some(key)).unwrap().url.clone()).collect();
+    assert_eq!(spread.len(), 3);
+
+    // The ring's hash is fixed, so every build maps keys the same way
+    let mapping: Vec<String> = (0..8).map(|key| upstream.select(Some(key)).unwrap().url.clone()).collect();
+    let expected = [2, 2, 1, 1, 1, 2, 1, 0].map(|i| format!("http://backend-{}", i));
+    assert_eq!(mapping, expected);
+}
+
+#[test]
+fn test_invalid_upstreams() {
+    let both = UpstreamConfig {
+        url: Some("http://a".to_string()),
+        endpoints: vec![EndpointConfig { url: "http://b".to_string(), weight: 1 }],
+        ..Default::default()
+    };
+    assert!(Upstream::new("both", &both).is_err());
+    assert!(Upstream::new("empty", &UpstreamConfig::default()).is_err());
+
+    let zero = UpstreamConfig {
+        endpoints: vec![EndpointConfig { url: "http://b".to_string(), weight: 0 }],
+        ..Default::default()
+    };
+    assert!(Upstream::new("zero", &zero).is_err());
+}
+
+#[test]
+fn test_http_version_config() {
+    let config = ProxyConfig::from_toml(
+        r#"
+        [upstreams.grpc]
+        url = "http://127.0.0.1:50051"
+        http_version = "http2"
+
+        [upstreams.web]
+        url = "http://127.0.0.1:8000"
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.upstreams["grpc"].http_version, HttpVersion::Http2);
+    assert_eq!(config.upstreams["web"].http_version, HttpVersion::Auto);
+
+    assert!(ProxyConfig::from_toml("[upstreams.web]\nurl = \"http://a\"\nhttp_version = \"h3\"").is_err());
+}
+
+#[actix_rt::test]
+async fn test_http2_prior_knowledge_to_upstream() {
+    // Serves HTTP/1.1 and h2c on the same port, answering with the version used
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let server = HttpServer::new(|| App::new().default_service(web::to(|req: HttpRequest| async move { format!("{:?}", req.version()) })))
+        .workers(1)
+        .listen_auto_h2c(listener)
+        .unwrap()
+        .run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+
+    for (http_version, expected) in [
+        (HttpVersion::Auto, "HTTP/1.1"),
+        (HttpVersion::Http1, "HTTP/1.1"),
+        (HttpVersion::Http2, "HTTP/2.0"),
+    ] {
+        let upstream = Upstream::new("backend", &UpstreamConfig { url: Some(url.clone()), http_version, ..Default::default() }).unwrap();
+        let endpoint = &upstream.endpoints()[0];
+        let client = upstream.client_builder(endpoint).build().unwrap();
+        let response = client.get(&endpoint.request_url).send().await.unwrap();
+
+        assert_eq!(response.text().await.unwrap(), expected, "{:?}", http_version);
+    }
+    handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn test_http2_client_through_proxy() {
+    // Answers with the method, how the body was framed and the body itself
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let upstream_url = format!("http://{}", listener.local_addr().unwrap());
+    let upstream = HttpServer::new(|| {
+        App::new().default_service(web::to(|req: HttpRequest, body: web::Bytes| async move {
+            let header = |name| req.headers().get(name).map_or("-", |v| v.to_str().unwrap()).to_string();
+            format!("{} {} {} {}", req.method(), header("transfer-encoding"), header("content-length"), String::from_utf8_lossy(&body))
+        }))
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    let upstream_handle = upstream.handle();
+    actix_rt::spawn(upstream);
+
+    let config = ProxyConfig::from_toml(&format!(
+        "[upstreams.backend]\nurl = \"{}\"\n[[routes]]\nname = \"backend\"\nupstream = \"backend\"\n",
+        upstream_url
+    ))
+    .unwrap();
+    let headers = actix_web::http::header::HeaderMap::new();
+    let request = RouteRequest { method: "GET", scheme: "http", host: None, path: "/", headers: &headers };
+    let route = RouteTable::new(&config, ForwardedHeaders::None).unwrap().find(&request).unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_url = format!("http://{}", listener.local_addr().unwrap());
+    let proxy = HttpServer::new(move || {
+        let route = Arc::clone(&route);
+        App::new().default_service(web::to(move |req, payload| forward_request(req, payload, Arc::clone(&route))))
+    })
+    .workers(1)
+    .listen_auto_h2c(listener)
+    .unwrap()
+    .run();
+    let proxy_handle = proxy.handle();
+    actix_rt::spawn(proxy);
+    let client = reqwest::Client::builder().http2_prior_knowledge().build().unwrap();
+
+    // Headers that end the stream mean no body, whatever the method
+    let response = client.delete(format!("{}/items/1", proxy_url)).send().await.unwrap();
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    assert_eq!(response.text().await.unwrap(), "DELETE - - ");
+
+    let chunks = ["streamed ", "body"].map(Ok::<_, std::io::Error>);
+    let response = client
+        .post(format!("{}/items", proxy_url))
+        .body(reqwest::Body::wrap_stream(futures::stream::iter(chunks)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.text().await.unwrap(), "POST chunked - streamed body");
+
+    let response = client.put(format!("{}/items/1", proxy_url)).body("sized").send().await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "PUT - 5 sized");
+
+    proxy_handle.stop(false).await;
+    upstream_handle.stop(false).await;
+}
+
+fn fixture(name: &str) -> String {
+    format!("{}/tests/fixtures/tls/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+#[actix_rt::test]
+async fn test_http2_negotiated_with_tls_upstream() {
+    // Offers h2 and HTTP/1.1 through ALPN, answering with the version used
+    let chain = rustls_pemfile::certs(&mut BufReader::new(File::open(fixture("a.pem")).unwrap())).collect::<Result<_, _>>().unwrap();
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(fixture("a.key")).unwrap())).unwrap().unwrap();
+    let server_config = ServerConfig::builder_with_provider(Arc::new(default_provider()))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(chain, key)
+        .unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("https://{}", listener.local_addr().unwrap());
+    let server = HttpServer::new(|| App::new().default_service(web::to(|req: HttpRequest| async move { format!("{:?}", req.version()) })))
+        .workers(1)
+        .listen_rustls_0_23(listener, server_config)
+        .unwrap()
+        .run();
+    let handle = server.handle();
+    actix_rt::spawn(server);
+
+    for (http_version, expected) in [
+        (HttpVersion::Auto, "HTTP/2.0"),
+        (HttpVersion::Http1, "HTTP/1.1"),
+        (HttpVersion::Http2, "HTTP/2.0"),
+    ] {
+        let tls = UpstreamTlsConfig {
+            ca: Some(fixture("ca.pem")),
+            server_name: Some("a.example.test".to_string()),
+            ..Default::default()
+        };
+        let config = UpstreamConfig { url: Some(url.clone()), http_version, tls: Some(tls), ..Default::default() };
+        let upstream = Upstream::new("backend", &config).unwrap();
+        let endpoint = &upstream.endpoints()[0];
+        let client = upstream.client_builder(endpoint).build().unwrap();
+        let response = client.get(&endpoint.request_url).send().await.unwrap();
+
+        assert_eq!(response.text().await.unwrap(), expected, "{:?}", http_version);
+    }
+    handle.stop(false).await;
+}
+
+#[actix_rt::test]
+async fn test_alpn_offered_without_tls_section() {
+    // Reads the ClientHello and hangs up, reporting the protocols offered
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("https://{}", listener.local_addr().unwrap());
+    let offered = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut acceptor = Acceptor::default();
+        let mut buf = [0; 4096];
+        loop {
+            let read = stream.read(&mut buf).await.unwrap();
+            acceptor.read_tls(&mut &buf[..read]).unwrap();
+            if let Some(accepted) = acceptor.accept().unwrap() {
+                return accepted.client_hello().alpn().map(|alpn| alpn.map(<[u8]>::to_vec).collect::<Vec<_>>());
+            }
+        }
+    });
+
+    let upstream = Upstream::new("backend", &UpstreamConfig { url: Some(url), ..Default::default() }).unwrap();
+    let endpoint = &upstream.endpoints()[0];
+    let client = upstream.client_builder(endpoint).build().unwrap();
+    assert!(client.get(&endpoint.request_url).send().await.is_err());
+    assert_eq!(offered.await.unwrap(), Some(vec![b"h2".to_vec(), b"http/1.1".to_vec()]));
+}
@@ -0,0 +1,255 @@
+use actix_web::{web, App, HttpServer};
+use futures::{SinkExt, StreamExt};
+use reverse_proxy::config::ProxyConfig;
+use reverse_proxy::handlers::buffer::{MessageBuffer, OverflowPolicy, PushError};
+use reverse_proxy::handlers::headers::ForwardedHeaders;
+use reverse_proxy::handlers::ws::ws_handler;
+use reverse_proxy::routing::{RouteRequest, RouteTable};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::Message;
+
+/// A WebSocket upstream that echoes messages, and answers `flood` with
+/// `count` binary messages of 64 KiB, reading nothing meanwhile. Handshakes
+/// are answered after `delay`.
+async fn upstream(count: usize, delay: Duration) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let mut ws = tokio_tungstenite::accept_async(stream).await.unwrap();
+                while let Some(Ok(message)) = ws.next().await {
+                    let sent = match message {
+                        Message::Text(text) if text == "flood" => {
+                            let mut sent = Ok(());
+                            for _ in 0..count {
+                                sent = ws.send(Message::Binary(vec![0; 64 * 1024])).await;
+                                if sent.is_err() {
+                                    break;
+                                }
+                            }
+                            sent
+                        }
+                        Message::Text(_) | Message::Binary(_) => ws.send(message).await,
+                        _ => break,
+                    };
+                    if sent.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+    url
+}
+
+/// Serves the route in `routes` with the WebSocket handler and returns its URL.
+fn proxy(routes: &str) -> String {
+    let config = ProxyConfig::from_toml(routes).unwrap();
+    let headers = actix_web::http::header::HeaderMap::new();
+    let request = RouteRequest { method: "GET", scheme: "http", host: None, path: "/", headers: &headers };
+    let route = RouteTable::new(&config, ForwardedHeaders::None).unwrap().find(&request).unwrap();
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    let server = HttpServer::new(move || {
+        let route = Arc::clone(&route);
+        App::new().default_service(web::to(move |req, payload| ws_handler(req, payload, Arc::clone(&route))))
+    })
+    .workers(1)
+    .listen(listener)
+    .unwrap()
+    .run();
+    actix_rt::spawn(server);
+    url
+}
+
+fn routes(upstream_url: &str, websocket: &str) -> String {
+    format!(
+        "[upstreams.ws]\nurl = \"{}\"\n[[routes]]\nname = \"ws\"\nupstream = \"ws\"\nkind = \"websocket\"\nwebsocket = {}\n",
+        upstream_url, websocket
+    )
+}
+
+#[actix_rt::test]
+async fn test_full_client_buffer_disconnects() {
+    let upstream_url = upstream(400, Duration::ZERO).await;
+    let proxy_url = proxy(&routes(&upstream_url, "{ client_buffer = 1, overflow = \"disconnect\", close_code = 1008 }"));
+    let (mut client, _) = tokio_tungstenite::connect_async(&proxy_url).await.unwrap();
+
+    client.send(Message::Text("hello".to_string())).await.unwrap();
+    let echo = timeout(Duration::from_secs(5), client.next()).await.unwrap();
+    assert_eq!(echo.unwrap().unwrap(), Message::Text("hello".to_string()));
+
+    // Not reading while 25 MiB arrive fills the socket and then the buffer
+    client.send(Message::Text("flood".to_string())).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let close = timeout(Duration::from_secs(10), async {
+        while let Some(Ok(message)) = client.next().await {
+            if let Message::Close(frame) = message {
+                return frame;
+            }
+        }
+        None
+    })
+    .await
+    .unwrap()
+    .unwrap();
+    assert_eq!(close.code, CloseCode::Policy);
+}
+
+#[actix_rt::test]
+async fn test_blocking_buffers_deliver_everything() {
+    let upstream_url = upstream(100, Duration::ZERO).await;
+    let proxy_url = proxy(&routes(&upstream_url, "{ client_buffer = 1, upstream_buffer = 1 }"));
+    let (mut client, _) = tokio_tungstenite::connect_async(&proxy_url).await.unwrap();
+
+    client.send(Message::Text("flood".to_string())).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let mut received = 0;
+    while received < 100 {
+        match timeout(Duration::from_secs(10), client.next()).await.unwrap() {
+            Some(Ok(Message::Binary(data))) => {
+                assert_eq!(data.len(), 64 * 1024);
+                received += 1;
+            }
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+}
+
+#[actix_rt::test]
+async fn test_blocking_buffers_full_both_ways() {
+    let upstream_url = upstream(400, Duration::ZERO).await;
+    let proxy_url = proxy(&routes(&upstream_url, "{ client_buffer = 1, upstream_buffer = 1 }"));
+    let (client, _) = tokio_tungstenite::connect_async(&proxy_url).await.unwrap();
+    let (mut write, mut read) = client.split();
+
+    // The client keeps sending while the upstream floods it without reading
+    let sender = actix_rt::spawn(async move {
+        write.send(Message::Text("flood".to_string())).await.unwrap();
+        for _ in 0..400 {
+            write.send(Message::Binary(vec![1; 32 * 1024])).await.unwrap();
+        }
+    });
+    let (mut flood, mut echoed) = (0, 0);
+    while flood + echoed < 800 {
+        match timeout(Duration::from_secs(10), read.next()).await.unwrap() {
+            Some(Ok(Message::Binary(data))) if data.len() == 64 * 1024 => flood += 1,
+            Some(Ok(Message::Binary(data))) if data.len() == 32 * 1024 => echoed += 1,
+            other => panic!("unexpected message: {:?}", other),
+        }
+    }
+    assert_eq!((flood, echoed), (400, 400));
+    sender.await.unwrap();
+}
+
+#[actix_rt::test]
+async fn test_messages_before_upstream_connects_are_replayed() {
+    let upstream_url = upstream(0, Duration::from_millis(300)).await;
+    let proxy_url = proxy(&routes(&upstream_url, "{ upstream_buffer = 2 }"));
+    let (mut client, _) = tokio_tungstenite::connect_async(&proxy_url).await.unwrap();
+
+    // The third message waits for room, which the connected upstream makes
+    for text in ["one", "two", "three"] {
+        client.send(Message::Text(text.to_string())).await.unwrap();
+    }
+    for text in ["one", "two", "three"] {
+        let echo = timeout(Duration::from_secs(5), client.next()).await.unwrap();
+        assert_eq!(echo.unwrap().unwrap(), Message::Text(text.to_string()));
+    }
+}
+
+#[actix_rt::test]
+async fn test_unreachable_upstream_closes_client() {
+    let proxy_url = proxy(&routes("ws://127.0.0.1:1", "{}"));
+    let (mut client, _) = tokio_tungstenite::connect_async(&proxy_url).await.unwrap();
+    client.send(Message::Text("hello".to_string())).await.unwrap();
+
+    match timeout(Duration::from_secs(5), client.next()).await.unwrap() {
+        Some(Ok(Message::Close(Some(frame)))) => {
+            assert_eq!(frame.code, CloseCode::Error);
+            assert_eq!(frame.reason, "Upstream unavailable");
+        }
+        other => panic!("expected a close frame, got {:?}", other),
+    }
+}
+
+fn text(text: &str) -> Message {
+    Message::Text(text.to_string())
+}
+
+#[tokio::test]
+async fn test_buffer_policies() {
+    let block = Arc::new(MessageBuffer::new(2, OverflowPolicy::Block));
+    block.push(text("1")).await.unwrap();
+    block.push(text("2")).await.unwrap();
+    let waiting = tokio::spawn({
+        let block = Arc::clone(&block);
+        async move { block.push(text("3")).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!waiting.is_finished());
+    assert_eq!(block.pop().await, Some(text("1")));
+    waiting.await.unwrap().unwrap();
+    assert_eq!(block.len(), 2);
+
+    // Queued messages are still taken after closing
+    block.close();
+    assert_eq!(block.push(text("4")).await, Err(PushError::Closed));
+    assert_eq!(MessageBuffer::into_stream(block).collect::<Vec<_>>().await, [text("2"), text("3")]);
+
+    let disconnect = MessageBuffer::new(1, OverflowPolicy::Disconnect);
+    disconnect.push(text("1")).await.unwrap();
+    assert_eq!(disconnect.push(text("2")).await, Err(PushError::Full));
+
+    // A close frame goes last even into a full buffer
+    disconnect.close_with(Message::Close(None));
+    assert_eq!(disconnect.len(), 2);
+    disconnect.close_with(Message::Close(None));
+    assert_eq!(disconnect.len(), 2);
+}
+
+#[tokio::test]
+async fn test_drop_oldest_keeps_control_frames() {
+    let buffer = Arc::new(MessageBuffer::new(3, OverflowPolicy::DropOldest));
+    buffer.push(Message::Ping(vec![1])).await.unwrap();
+    buffer.push(text("1")).await.unwrap();
+    buffer.push(text("2")).await.unwrap();
+    buffer.push(text("3")).await.unwrap();
+    assert_eq!(buffer.pop().await, Some(Message::Ping(vec![1])));
+    assert_eq!(buffer.pop().await, Some(text("2")));
+
+    // With only control frames queued, the next message waits for room
+    let controls = Arc::new(MessageBuffer::new(1, OverflowPolicy::DropOldest));
+    controls.push(Message::Close(None)).await.unwrap();
+    let waiting = tokio::spawn({
+        let controls = Arc::clone(&controls);
+        async move { controls.push(text("late")).await }
+    });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!waiting.is_finished());
+    assert_eq!(controls.pop().await, Some(Message::Close(None)));
+    waiting.await.unwrap().unwrap();
+    assert_eq!(controls.pop().await, Some(text("late")));
+}
+
+#[test]
+fn test_websocket_config() {
+    let config = ProxyConfig::from_toml(&routes("ws://a", "{}")).unwrap();
+    let websocket = config.routes[0].websocket;
+    assert_eq!((websocket.client_buffer, websocket.upstream_buffer), (64, 64));
+    assert_eq!(websocket.overflow, OverflowPolicy::Block);
+    assert_eq!(websocket.close_code, 1013);
+
+    for invalid in ["{ close_code = 1000 }", "{ client_buffer = 0 }"] {
+        let config = ProxyConfig::from_toml(&routes("ws://a", invalid)).unwrap();
+        assert!(RouteTable::new(&config, ForwardedHeaders::None).is_err(), "{}", invalid);
+    }
+    assert!(ProxyConfig::from_toml(&routes("ws://a", "{ overflow = \"drop\" }")).is_err());
+}